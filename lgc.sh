@@ -1,3 +1,10 @@
 #!/bin/bash
+# runs the debug build. source it instead to define the `lgc` function,
+# which cds into the directories lgc switches to.
 SCRIPT_DIR=$( cd -- "$( dirname -- "${BASH_SOURCE[0]}" )" &> /dev/null && pwd )
-"$SCRIPT_DIR"/target/debug/lazy-git-checkout "$@"
\ No newline at end of file
+if [[ "${BASH_SOURCE[0]}" != "$0" ]]; then
+    eval "$("$SCRIPT_DIR"/target/debug/lazy-git-checkout shell-init bash)"
+    unset SCRIPT_DIR
+else
+    "$SCRIPT_DIR"/target/debug/lazy-git-checkout "$@"
+fi
@@ -0,0 +1,335 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Output,
+    rc::Rc,
+};
+
+use anyhow::{anyhow, Result};
+
+use crate::core::{BranchInfo, BranchKind, BranchRef, StashMode};
+
+#[cfg(feature = "libgit2")]
+const BACKEND_ENV: &str = "LAZY_GIT_CHECKOUT_BACKEND";
+
+#[cfg(test)]
+mod fake;
+#[cfg(feature = "libgit2")]
+mod libgit2;
+
+#[cfg(test)]
+pub use self::fake::FakeBackend;
+
+#[cfg(feature = "libgit2")]
+pub use self::libgit2::Libgit2Backend;
+
+/// the git operations the checkout flow depends on. everything else
+/// `core::Git` does still goes through the git cli.
+pub trait GitBackend {
+    /// name of the checked out branch, `HEAD` when detached
+    fn current_branch(&self) -> Result<String>;
+
+    /// local branches followed by remote ones, each sorted by name
+    fn branches(&self) -> Result<Vec<BranchRef>>;
+
+    /// switches to a local branch, anything else is checked out detached
+    fn checkout(&self, branch: &str) -> Result<()>;
+
+    /// creates the local branch `name` at `upstream` and sets it as
+    /// the branch's upstream, `upstream` being `<remote>/<branch>`.
+    fn create_branch(&self, name: &str, upstream: &str) -> Result<()>;
+
+    /// stashes the working tree and returns the sha of the new stash,
+    /// or None if there was nothing to stash.
+    fn stash(&self, message: &str, mode: StashMode) -> Result<Option<String>>;
+
+    /// returns (sha, subject) for every entry in the stash list, newest first
+    fn stash_list(&self) -> Result<Vec<(String, String)>>;
+
+    /// sha `name` points to, None if the ref doesn't exist
+    fn read_ref(&self, name: &str) -> Result<Option<String>>;
+
+    /// sha of the commit `rev` points to, tags are peeled. None if
+    /// `rev` doesn't name a commit.
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>>;
+
+    /// points the ref `name` to `sha`, creating it if needed
+    fn update_ref(&self, name: &str, sha: &str) -> Result<()>;
+
+    /// removes the ref `name`
+    fn delete_ref(&self, name: &str) -> Result<()>;
+
+    /// absolute path of the repository's git directory
+    fn git_dir(&self) -> Result<PathBuf>;
+
+    /// porcelain status of the working tree, only compared to itself.
+    /// empty when there are no changes or untracked files.
+    fn status(&self) -> Result<String>;
+
+    /// pops the stash `sha`, restoring the index too when `index` is set
+    fn stash_pop(&self, sha: &str, index: bool) -> Result<()>;
+
+    /// last commit and upstream tracking status of `branch`
+    fn branch_info(&self, branch: &str) -> Result<BranchInfo>;
+}
+
+/// the in-process backend is used when lgc is built with it, unless
+/// $LAZY_GIT_CHECKOUT_BACKEND is set to `cli`.
+pub fn default_backend(path: &str) -> Rc<dyn GitBackend> {
+    #[cfg(feature = "libgit2")]
+    if std::env::var(BACKEND_ENV).as_deref() != Ok("cli") {
+        return Rc::new(Libgit2Backend::new(path.to_string()));
+    }
+    Rc::new(CliBackend::new(path.to_string()))
+}
+
+/// runs the git binary for every operation
+pub struct CliBackend {
+    path: String,
+}
+
+impl CliBackend {
+    /// runs git in the repository at `path`
+    pub fn new(path: String) -> CliBackend {
+        CliBackend { path }
+    }
+
+    // whether there is anything `mode` would stash
+    fn has_changes(&self, mode: StashMode) -> Result<bool> {
+        let mut command = vec!["status", "--porcelain"];
+        match mode {
+            StashMode::Tracked => command.push("--untracked-files=no"),
+            StashMode::IncludeUntracked => command.push("--untracked-files=all"),
+            StashMode::All => command.extend(["--untracked-files=all", "--ignored"]),
+        }
+        let output = run_git_command(self.path.as_str(), command)?;
+        Ok(!output.stdout.is_empty())
+    }
+
+    fn last_stash_sha(&self) -> Result<Option<String>> {
+        let output = run_git_command(
+            self.path.as_str(),
+            vec!["stash", "list", "-n", "1", "--format=%H"],
+        )?;
+        let sha = String::from_utf8(output.stdout)?.trim().to_string();
+        Ok(Some(sha).filter(|s| !s.is_empty()))
+    }
+}
+
+impl GitBackend for CliBackend {
+    // the full name is asked for since the abbreviated one becomes
+    // `heads/<branch>` when a tag has the branch's name
+    fn current_branch(&self) -> Result<String> {
+        let output = run_git_command(
+            self.path.as_str(),
+            vec!["rev-parse", "--symbolic-full-name", "HEAD"],
+        )?;
+        let name = String::from_utf8(output.stdout)?;
+        let name = name.trim();
+        Ok(name.strip_prefix("refs/heads/").unwrap_or(name).to_string())
+    }
+
+    fn branches(&self) -> Result<Vec<BranchRef>> {
+        let output = run_git_command(
+            self.path.as_str(),
+            vec![
+                "for-each-ref",
+                "--format=%(refname)%00%(objectname)%00%(upstream:short)%00%(committerdate:unix)%00%(symref)",
+                "refs/heads",
+                "refs/remotes",
+            ],
+        )?;
+        let mut branches = Vec::new();
+        for line in String::from_utf8(output.stdout)?.lines() {
+            let fields = line.split('\0').collect::<Vec<&str>>();
+            let [refname, sha, upstream, date, symref] = fields[..] else {
+                continue;
+            };
+            let (name, kind) = match (
+                refname.strip_prefix("refs/heads/"),
+                refname.strip_prefix("refs/remotes/"),
+            ) {
+                (Some(name), _) => (name, BranchKind::Local),
+                (None, Some(name)) if symref.is_empty() => (name, BranchKind::Remote),
+                (None, Some(name)) => (name, BranchKind::Head),
+                _ => continue,
+            };
+            branches.push(BranchRef {
+                name: name.to_string(),
+                kind,
+                upstream: Some(upstream.to_string()).filter(|u| !u.is_empty()),
+                sha: sha.to_string(),
+                date: date.parse().unwrap_or(0),
+            });
+        }
+        Ok(branches)
+    }
+
+    fn checkout(&self, branch: &str) -> Result<()> {
+        stream_git_command(self.path.as_str(), vec!["checkout", branch])
+    }
+
+    fn create_branch(&self, name: &str, upstream: &str) -> Result<()> {
+        run_git_command(
+            self.path.as_str(),
+            vec!["branch", "--track", name, upstream],
+        )?;
+        Ok(())
+    }
+
+    fn stash(&self, message: &str, mode: StashMode) -> Result<Option<String>> {
+        if !self.has_changes(mode)? {
+            return Ok(None);
+        }
+        let before = self.last_stash_sha()?;
+        let mut command = vec!["stash", "push", "-m", message];
+        command.extend(mode.stash_args());
+        stream_git_command(self.path.as_str(), command)?;
+        let after = self.last_stash_sha()?;
+        if after == before {
+            return Ok(None);
+        }
+        Ok(after)
+    }
+
+    fn stash_list(&self) -> Result<Vec<(String, String)>> {
+        let output = run_git_command(self.path.as_str(), vec!["stash", "list", "--format=%H %gs"])?;
+        let stashes = String::from_utf8(output.stdout)?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(sha, subject)| (sha.to_string(), subject.to_string()))
+            .collect();
+        Ok(stashes)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<String>> {
+        let output = run_git_command(
+            self.path.as_str(),
+            vec!["for-each-ref", "--format=%(objectname) %(refname)", name],
+        )?;
+        let sha = String::from_utf8(output.stdout)?
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .find(|(_, refname)| *refname == name)
+            .map(|(sha, _)| sha.to_string());
+        Ok(sha)
+    }
+
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>> {
+        let rev = format!("{rev}^{{commit}}");
+        // exits with an error when `rev` can't be resolved
+        let Ok(output) = run_git_command(
+            self.path.as_str(),
+            vec!["rev-parse", "--verify", "--quiet", rev.as_str()],
+        ) else {
+            return Ok(None);
+        };
+        Ok(Some(String::from_utf8(output.stdout)?.trim().to_string()))
+    }
+
+    fn update_ref(&self, name: &str, sha: &str) -> Result<()> {
+        run_git_command(self.path.as_str(), vec!["update-ref", name, sha])?;
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<()> {
+        run_git_command(self.path.as_str(), vec!["update-ref", "-d", name])?;
+        Ok(())
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        let output = run_git_command(self.path.as_str(), vec!["rev-parse", "--absolute-git-dir"])?;
+        let git_dir = String::from_utf8(output.stdout)?;
+        Ok(Path::new(git_dir.trim()).to_path_buf())
+    }
+
+    fn status(&self) -> Result<String> {
+        let output = run_git_command(self.path.as_str(), vec!["status", "--porcelain"])?;
+        Ok(String::from_utf8(output.stdout)?)
+    }
+
+    fn stash_pop(&self, sha: &str, index: bool) -> Result<()> {
+        let stash_ref = stash_ref(&self.stash_list()?, sha)?;
+        let mut command = vec!["stash", "pop"];
+        if index {
+            command.push("--index");
+        }
+        command.push(stash_ref.as_str());
+        stream_git_command(self.path.as_str(), command)
+    }
+
+    fn branch_info(&self, branch: &str) -> Result<BranchInfo> {
+        let output = run_git_command(
+            self.path.as_str(),
+            vec!["log", "-1", "--format=%s%x00%an%x00%cr", branch, "--"],
+        )?;
+        let log = String::from_utf8(output.stdout)?;
+        let mut parts = log.trim_end().splitn(3, '\0');
+        let mut info = BranchInfo {
+            subject: parts.next().unwrap_or_default().to_string(),
+            author: parts.next().unwrap_or_default().to_string(),
+            date: parts.next().unwrap_or_default().to_string(),
+            upstream: None,
+            ahead: 0,
+            behind: 0,
+        };
+        // fails when the branch has no upstream
+        let upstream_ref = format!("{branch}@{{upstream}}");
+        let Ok(output) = run_git_command(
+            self.path.as_str(),
+            vec!["rev-parse", "--abbrev-ref", upstream_ref.as_str()],
+        ) else {
+            return Ok(info);
+        };
+        let upstream = String::from_utf8(output.stdout)?.trim().to_string();
+        let range = format!("{branch}...{upstream}");
+        let output = run_git_command(
+            self.path.as_str(),
+            vec!["rev-list", "--left-right", "--count", range.as_str()],
+        )?;
+        let counts = String::from_utf8(output.stdout)?;
+        let mut counts = counts.split_whitespace().map(|c| c.parse().unwrap_or(0));
+        info.ahead = counts.next().unwrap_or(0);
+        info.behind = counts.next().unwrap_or(0);
+        info.upstream = Some(upstream);
+        Ok(info)
+    }
+}
+
+// returns the `stash@{n}` ref pointing to `sha` in `stashes`, as
+// returned by `stash_list`. the stash list has to be read right
+// before using the ref since the index shifts whenever a stash is
+// pushed or dropped.
+pub(crate) fn stash_ref(stashes: &[(String, String)], sha: &str) -> Result<String> {
+    Ok(format!("stash@{{{}}}", stash_index(stashes, sha)?))
+}
+
+pub(crate) fn stash_index(stashes: &[(String, String)], sha: &str) -> Result<usize> {
+    stashes
+        .iter()
+        .position(|(s, _)| s == sha)
+        .ok_or(anyhow!("stash {sha} not found"))
+}
+
+pub(crate) fn run_git_command(path: &str, command: Vec<&str>) -> Result<Output> {
+    let output = std::process::Command::new("git")
+        .args(command)
+        .current_dir(path)
+        .output()?;
+    if !output.status.success() {
+        let error = String::from_utf8(output.stderr)?;
+        return Err(anyhow::anyhow!(error));
+    }
+    Ok(output)
+}
+
+pub(crate) fn stream_git_command(path: &str, command: Vec<&str>) -> Result<()> {
+    let status = std::process::Command::new("git")
+        .args(command.as_slice())
+        .current_dir(path)
+        .spawn()?
+        .wait()?;
+    if !status.success() {
+        return Err(anyhow!("`git {}` failed ({status})", command.join(" ")));
+    }
+    Ok(())
+}
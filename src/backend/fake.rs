@@ -0,0 +1,203 @@
+use std::{
+    cell::{RefCell, RefMut},
+    collections::HashMap,
+    path::PathBuf,
+};
+
+use anyhow::{anyhow, bail, Result};
+
+use super::GitBackend;
+use crate::core::{BranchInfo, BranchKind, BranchRef, StashMode};
+
+// in-memory repository for tests. the working tree is kept as the
+// lines `git status --porcelain` would print for it.
+pub struct FakeBackend {
+    git_dir: PathBuf,
+    repo: RefCell<FakeRepo>,
+}
+
+#[derive(Debug, Default)]
+pub struct FakeRepo {
+    pub head: String,
+    pub branches: Vec<String>,
+    pub changes: Vec<String>,
+    // newest first, like the stash list
+    pub stashes: Vec<FakeStash>,
+    pub refs: HashMap<String, String>,
+    stash_count: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct FakeStash {
+    pub sha: String,
+    pub subject: String,
+    pub changes: Vec<String>,
+}
+
+impl FakeBackend {
+    // starts on the first of `branches`. the journal is written to
+    // `git_dir` so it must exist.
+    pub fn new(git_dir: PathBuf, branches: &[&str]) -> FakeBackend {
+        let repo = FakeRepo {
+            head: branches[0].to_string(),
+            branches: branches.iter().map(|b| b.to_string()).collect(),
+            ..Default::default()
+        };
+        FakeBackend {
+            git_dir,
+            repo: RefCell::new(repo),
+        }
+    }
+
+    pub fn repo(&self) -> RefMut<'_, FakeRepo> {
+        self.repo.borrow_mut()
+    }
+}
+
+impl GitBackend for FakeBackend {
+    fn current_branch(&self) -> Result<String> {
+        Ok(self.repo().head.clone())
+    }
+
+    // branches named `remotes/<remote>/<name>` are remote ones
+    fn branches(&self) -> Result<Vec<BranchRef>> {
+        let branches = self
+            .repo()
+            .branches
+            .iter()
+            .map(|b| {
+                let (name, kind) = match b.strip_prefix("remotes/") {
+                    Some(name) => (name, BranchKind::Remote),
+                    None => (b.as_str(), BranchKind::Local),
+                };
+                BranchRef {
+                    name: name.to_string(),
+                    kind,
+                    upstream: None,
+                    sha: format!("{:040x}", 0),
+                    date: 0,
+                }
+            })
+            .collect();
+        Ok(branches)
+    }
+
+    fn checkout(&self, branch: &str) -> Result<()> {
+        let mut repo = self.repo();
+        if !repo.branches.iter().any(|b| b == branch) {
+            bail!("pathspec '{branch}' did not match any file(s) known to git");
+        }
+        repo.head = branch.to_string();
+        Ok(())
+    }
+
+    fn create_branch(&self, name: &str, upstream: &str) -> Result<()> {
+        let mut repo = self.repo();
+        if !repo.branches.contains(&format!("remotes/{upstream}")) {
+            bail!("{upstream} is not a remote branch");
+        }
+        repo.branches.push(name.to_string());
+        Ok(())
+    }
+
+    fn stash(&self, message: &str, mode: StashMode) -> Result<Option<String>> {
+        let mut repo = self.repo();
+        let (stashed, kept): (Vec<String>, Vec<String>) =
+            repo.changes.iter().cloned().partition(|c| match mode {
+                StashMode::Tracked => !c.starts_with("??") && !c.starts_with("!!"),
+                StashMode::IncludeUntracked => !c.starts_with("!!"),
+                StashMode::All => true,
+            });
+        if stashed.is_empty() {
+            return Ok(None);
+        }
+        repo.changes = kept;
+        repo.stash_count += 1;
+        let stash = FakeStash {
+            sha: format!("{:040x}", repo.stash_count),
+            subject: format!("On {}: {message}", repo.head),
+            changes: stashed,
+        };
+        let sha = stash.sha.clone();
+        repo.stashes.insert(0, stash);
+        Ok(Some(sha))
+    }
+
+    fn stash_list(&self) -> Result<Vec<(String, String)>> {
+        let stashes = self
+            .repo()
+            .stashes
+            .iter()
+            .map(|s| (s.sha.clone(), s.subject.clone()))
+            .collect();
+        Ok(stashes)
+    }
+
+    // branch refs are made up from the branch list
+    fn read_ref(&self, name: &str) -> Result<Option<String>> {
+        let repo = self.repo();
+        let branch = match (name.strip_prefix("refs/heads/"), name.strip_prefix("refs/")) {
+            (Some(local), _) => Some(local.to_string()),
+            (None, Some(remote)) if remote.starts_with("remotes/") => Some(remote.to_string()),
+            _ => None,
+        };
+        match branch {
+            Some(branch) if repo.branches.contains(&branch) => Ok(Some(format!("{:040x}", 0))),
+            Some(_) => Ok(None),
+            None => Ok(repo.refs.get(name).cloned()),
+        }
+    }
+
+    // branches all point to the same commit
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>> {
+        let repo = self.repo();
+        if repo.branches.iter().any(|b| b == rev) {
+            return Ok(Some(format!("{:040x}", 0)));
+        }
+        Ok(repo.refs.get(rev).cloned())
+    }
+
+    fn update_ref(&self, name: &str, sha: &str) -> Result<()> {
+        self.repo().refs.insert(name.to_string(), sha.to_string());
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<()> {
+        self.repo().refs.remove(name);
+        Ok(())
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        Ok(self.git_dir.clone())
+    }
+
+    fn status(&self) -> Result<String> {
+        Ok(self.repo().changes.join("\n"))
+    }
+
+    fn stash_pop(&self, sha: &str, _index: bool) -> Result<()> {
+        let mut repo = self.repo();
+        let ix = repo
+            .stashes
+            .iter()
+            .position(|s| s.sha == sha)
+            .ok_or(anyhow!("stash {sha} not found"))?;
+        let stash = repo.stashes.remove(ix);
+        repo.changes.extend(stash.changes);
+        Ok(())
+    }
+
+    fn branch_info(&self, branch: &str) -> Result<BranchInfo> {
+        if !self.repo().branches.iter().any(|b| b == branch) {
+            bail!("unknown branch {branch}");
+        }
+        Ok(BranchInfo {
+            subject: format!("last commit on {branch}"),
+            author: "lgc".to_string(),
+            date: "2 days ago".to_string(),
+            upstream: None,
+            ahead: 0,
+            behind: 0,
+        })
+    }
+}
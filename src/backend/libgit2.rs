@@ -0,0 +1,204 @@
+use anyhow::{anyhow, Result};
+use std::path::PathBuf;
+
+use git2::{
+    build::CheckoutBuilder, BranchType, ErrorCode, ObjectType, Oid, Repository, StashFlags,
+    StatusOptions,
+};
+
+use super::{CliBackend, GitBackend};
+use crate::core::{BranchInfo, BranchKind, BranchRef, StashMode};
+
+/// talks to the repository in-process through libgit2, the repository
+/// is opened on every call so changes made by the git cli are seen.
+pub struct Libgit2Backend {
+    path: String,
+}
+
+impl Libgit2Backend {
+    pub fn new(path: String) -> Libgit2Backend {
+        Libgit2Backend { path }
+    }
+
+    fn open(&self) -> Result<Repository> {
+        Ok(Repository::discover(self.path.as_str())?)
+    }
+}
+
+impl GitBackend for Libgit2Backend {
+    fn current_branch(&self) -> Result<String> {
+        let repo = self.open()?;
+        let head = repo.head()?;
+        if !head.is_branch() {
+            return Ok("HEAD".to_string());
+        }
+        let name = head
+            .shorthand()
+            .ok_or(anyhow!("branch name is not utf-8"))?;
+        Ok(name.to_string())
+    }
+
+    fn branches(&self) -> Result<Vec<BranchRef>> {
+        let repo = self.open()?;
+        let mut branches = Vec::new();
+        for branch in repo.branches(None)? {
+            let (branch, branch_type) = branch?;
+            let Some(name) = branch.name()? else {
+                continue;
+            };
+            let kind = match branch_type {
+                BranchType::Local => BranchKind::Local,
+                _ if branch.get().symbolic_target().is_some() => BranchKind::Head,
+                BranchType::Remote => BranchKind::Remote,
+            };
+            let upstream = match branch.upstream() {
+                Ok(upstream) => upstream.name()?.map(|u| u.to_string()),
+                Err(_) => None,
+            };
+            let commit = branch.get().peel_to_commit()?;
+            branches.push(BranchRef {
+                name: name.to_string(),
+                kind,
+                upstream,
+                sha: commit.id().to_string(),
+                date: commit.time().seconds(),
+            });
+        }
+        branches.sort_by(|a, b| {
+            (a.kind != BranchKind::Local, &a.name).cmp(&(b.kind != BranchKind::Local, &b.name))
+        });
+        Ok(branches)
+    }
+
+    // switches to a local branch, anything else is checked out
+    // detached like the git cli does. local branches win over tags
+    // with the same name.
+    fn checkout(&self, branch: &str) -> Result<()> {
+        let repo = self.open()?;
+        let local = match repo.find_branch(branch, BranchType::Local) {
+            Ok(local) => Some(local),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        match local {
+            Some(local) => {
+                let reference = local.into_reference();
+                let target = reference.peel(ObjectType::Commit)?;
+                repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))?;
+                let refname = reference
+                    .name()
+                    .ok_or(anyhow!("branch name is not utf-8"))?;
+                repo.set_head(refname)?;
+            }
+            None => {
+                let target = repo.revparse_single(branch)?.peel(ObjectType::Commit)?;
+                repo.checkout_tree(&target, Some(CheckoutBuilder::new().safe()))?;
+                repo.set_head_detached(target.id())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn create_branch(&self, name: &str, upstream: &str) -> Result<()> {
+        let repo = self.open()?;
+        let commit = repo
+            .find_branch(upstream, BranchType::Remote)?
+            .get()
+            .peel_to_commit()?;
+        let mut branch = repo.branch(name, &commit, false)?;
+        branch.set_upstream(Some(upstream))?;
+        Ok(())
+    }
+
+    fn stash(&self, message: &str, mode: StashMode) -> Result<Option<String>> {
+        let mut repo = self.open()?;
+        let signature = repo.signature()?;
+        let flags = match mode {
+            StashMode::Tracked => StashFlags::DEFAULT,
+            StashMode::IncludeUntracked => StashFlags::INCLUDE_UNTRACKED,
+            StashMode::All => StashFlags::INCLUDE_UNTRACKED | StashFlags::INCLUDE_IGNORED,
+        };
+        match repo.stash_save(&signature, message, Some(flags)) {
+            Ok(oid) => Ok(Some(oid.to_string())),
+            // nothing to stash
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn stash_list(&self) -> Result<Vec<(String, String)>> {
+        let mut repo = self.open()?;
+        let mut stashes = Vec::new();
+        repo.stash_foreach(|_, subject, oid| {
+            stashes.push((oid.to_string(), subject.to_string()));
+            true
+        })?;
+        Ok(stashes)
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<String>> {
+        let repo = self.open()?;
+        let sha = match repo.find_reference(name) {
+            Ok(reference) => reference.target().map(|oid| oid.to_string()),
+            Err(e) if e.code() == ErrorCode::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(sha)
+    }
+
+    fn resolve_commit(&self, rev: &str) -> Result<Option<String>> {
+        let repo = self.open()?;
+        let sha = match repo.revparse_single(rev) {
+            Ok(object) => object.peel_to_commit().ok().map(|c| c.id().to_string()),
+            Err(e) if matches!(e.code(), ErrorCode::NotFound | ErrorCode::Ambiguous) => None,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(sha)
+    }
+
+    fn update_ref(&self, name: &str, sha: &str) -> Result<()> {
+        let repo = self.open()?;
+        repo.reference(name, Oid::from_str(sha)?, true, "lazy-git-checkout")?;
+        Ok(())
+    }
+
+    fn delete_ref(&self, name: &str) -> Result<()> {
+        let repo = self.open()?;
+        match repo.find_reference(name) {
+            Ok(mut reference) => reference.delete()?,
+            Err(e) if e.code() == ErrorCode::NotFound => {}
+            Err(e) => return Err(e.into()),
+        };
+        Ok(())
+    }
+
+    fn git_dir(&self) -> Result<PathBuf> {
+        let repo = self.open()?;
+        Ok(repo.path().canonicalize()?)
+    }
+
+    fn status(&self) -> Result<String> {
+        let repo = self.open()?;
+        let mut status = String::new();
+        // like `git status --porcelain`, ignored files are left out
+        let mut opts = StatusOptions::new();
+        opts.include_untracked(true).include_ignored(false);
+        for entry in repo.statuses(Some(&mut opts))?.iter() {
+            let path = entry.path().unwrap_or_default();
+            status.push_str(format!("{:?} {path}\n", entry.status()).as_str());
+        }
+        Ok(status)
+    }
+
+    // libgit2 refuses to apply a stash that conflicts instead of
+    // leaving conflict markers like git does, so popping goes through
+    // the git cli to keep the conflict resolution flow working.
+    fn stash_pop(&self, sha: &str, index: bool) -> Result<()> {
+        CliBackend::new(self.path.clone()).stash_pop(sha, index)
+    }
+
+    // relative dates and ahead/behind counts come formatted from git
+    fn branch_info(&self, branch: &str) -> Result<BranchInfo> {
+        CliBackend::new(self.path.clone()).branch_info(branch)
+    }
+}
@@ -1,9 +1,16 @@
-use clap::Parser;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+use lazy_git_checkout::{shell::Shell, StashMode};
 
 #[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about)]
 pub struct CLIArgs {
-    /// Add branch to checkout to
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Add branch, tag or commit to checkout to
     #[clap(short, long)]
     pub add: Option<String>,
 
@@ -26,4 +33,70 @@ pub struct CLIArgs {
     /// Checkout with stash
     #[clap(short, long)]
     pub checkout: Option<String>,
+
+    /// Check out even if a merge, rebase, cherry-pick or bisect is in progress
+    #[clap(short, long)]
+    pub force: bool,
+
+    /// Files to stash when changing branches, overrides the project setting
+    #[clap(long, value_enum)]
+    pub stash_mode: Option<StashMode>,
+
+    /// Save the files to stash when changing branches for the current project
+    #[clap(long, value_enum)]
+    pub set_stash_mode: Option<StashMode>,
+
+    /// Path to the database file [env: LAZY_GIT_CHECKOUT_DB]
+    #[clap(long)]
+    pub db: Option<PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Inspect and fix the database
+    Db {
+        #[command(subcommand)]
+        command: DBCommand,
+    },
+
+    /// Finish or undo a checkout that was interrupted
+    Recover {
+        /// Run the remaining steps of the checkout
+        #[clap(long, conflicts_with = "undo")]
+        finish: bool,
+
+        /// Go back to the branch and changes from before the checkout
+        #[clap(long)]
+        undo: bool,
+    },
+
+    /// Check out branches in their own worktree instead of stashing
+    Worktree {
+        #[command(subcommand)]
+        command: WorktreeCommand,
+    },
+
+    /// Print a shell function that cds into the worktrees lgc switches to,
+    /// e.g. `eval "$(lazy-git-checkout shell-init bash)"`
+    ShellInit { shell: Shell },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum WorktreeCommand {
+    /// Check out a saved branch in its own worktree
+    Enable { branch: String },
+    /// Check out a saved branch in place again, stashing changes
+    Disable { branch: String },
+    /// List the worktrees of the current project
+    List,
+    /// Forget worktrees whose directory was removed
+    Prune,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum DBCommand {
+    /// Report orphan lines, duplicates and missing project directories
+    Check,
+    /// Rewrite the database without the problems reported by `check`
+    Repair,
 }
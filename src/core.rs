@@ -1,46 +1,190 @@
-use std::{io::Write, process::Output, vec};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::Output,
+    rc::Rc,
+    vec,
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
 
-const DB_PATH: &str = "/etc/lazy-git-checkout.db.txt";
+use crate::backend::{self, GitBackend};
+
+const LEGACY_DB_PATH: &str = "/etc/lazy-git-checkout.db.txt";
+const DB_DIR_NAME: &str = "lazy-git-checkout";
+const DB_FILE_NAME: &str = "db.json";
+const DB_PATH_ENV: &str = "LAZY_GIT_CHECKOUT_DB";
 const PROJECT_PATH_DELIMITER: &str = ";;;;";
+const STASH_MESSAGE_PREFIX: &str = "lazy-git-checkout:";
+const STASH_REF_PREFIX: &str = "refs/lazy-git-checkout/stashes/";
+// set once the stashes made before they were tracked by ref are adopted
+const LEGACY_STASHES_REF: &str = "refs/lazy-git-checkout/legacy-stashes-adopted";
+const JOURNAL_FILE_NAME: &str = "lazy-git-checkout-journal.json";
 
-#[derive(Debug, Clone)]
+// version of the structured db format, bump when the schema changes.
+const DB_VERSION: u32 = 1;
+
+/// resolves the default db location in order of precedence:
+/// 1. $LAZY_GIT_CHECKOUT_DB
+/// 2. $XDG_DATA_HOME/lazy-git-checkout/db.json
+/// 3. ~/.local/share/lazy-git-checkout/db.json
+pub fn db_path() -> Result<PathBuf> {
+    resolve_db_path(non_empty_env)
+}
+
+fn resolve_db_path(env: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    match env(DB_PATH_ENV) {
+        Some(path) => Ok(PathBuf::from(path)),
+        None => default_db_path(env),
+    }
+}
+
+// the location the db is migrated to from /etc, the env var and the
+// --db flag point elsewhere.
+fn default_db_path(env: impl Fn(&str) -> Option<String>) -> Result<PathBuf> {
+    let data_dir = match env("XDG_DATA_HOME") {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            let home = env("HOME").ok_or(anyhow!("could not resolve home directory"))?;
+            PathBuf::from(home).join(".local").join("share")
+        }
+    };
+    Ok(data_dir.join(DB_DIR_NAME).join(DB_FILE_NAME))
+}
+
+fn non_empty_env(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|v| !v.is_empty())
+}
+
+/// a branch, tag or commit saved in a project to check out
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Branch {
     pub name: String,
+    #[serde(default)]
+    pub kind: TargetKind,
+    /// checked out in its own worktree instead of stashing
+    #[serde(default)]
+    pub worktree: bool,
 }
 
-#[derive(Debug, Clone)]
+impl Branch {
+    /// a branch saved with the default settings
+    pub fn new(name: String) -> Branch {
+        Branch {
+            name,
+            kind: TargetKind::Branch,
+            worktree: false,
+        }
+    }
+}
+
+/// what a saved checkout target points to. tags and commits are
+/// checked out with a detached HEAD.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetKind {
+    #[default]
+    Branch,
+    Tag,
+    /// saved by its full sha
+    Commit,
+}
+
+/// which files get stashed before changing branches
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum StashMode {
+    /// Only changes to tracked files
+    #[default]
+    Tracked,
+    /// Tracked changes and untracked files
+    IncludeUntracked,
+    /// Tracked changes, untracked and ignored files
+    All,
+}
+
+impl StashMode {
+    /// arguments added to `git stash push` for this mode
+    pub fn stash_args(&self) -> Vec<&'static str> {
+        match self {
+            StashMode::Tracked => vec![],
+            StashMode::IncludeUntracked => vec!["--include-untracked"],
+            StashMode::All => vec!["--all"],
+        }
+    }
+}
+
+/// how `Git::checkout` stashes and what it refuses
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CheckoutOptions {
+    pub stash_mode: StashMode,
+    /// check out even if a merge, rebase, cherry-pick or bisect is in progress
+    pub force: bool,
+}
+
+impl CheckoutOptions {
+    /// the stash mode passed as argument takes precedence over the project's
+    pub fn for_project(
+        project: &Project,
+        stash_mode: Option<StashMode>,
+        force: bool,
+    ) -> CheckoutOptions {
+        CheckoutOptions {
+            stash_mode: stash_mode.unwrap_or(project.stash_mode),
+            force,
+        }
+    }
+}
+
+/// a repository tracked in the db and its saved branches
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Project {
     pub path: String,
     pub branches: Vec<Branch>,
+    #[serde(default)]
+    pub stash_mode: StashMode,
 }
 
 impl Project {
+    /// a project without saved branches
     pub fn new(path: String) -> Project {
         Project {
             path,
             branches: Vec::new(),
+            stash_mode: StashMode::default(),
         }
     }
 
-    fn add_branch(&mut self, branch: String) {
-        self.branches.push(Branch { name: branch });
+    fn add_branch(&mut self, branch: Branch) {
+        self.branches.push(branch);
     }
 
     fn remove_branch(&mut self, branch: String) {
         self.branches.retain(|b| b.name != branch);
     }
+
+    // moves `branch` `delta` places down the list, wrapping around
+    // its ends, and returns its new position.
+    fn move_branch(&mut self, branch: &str, delta: isize) -> Option<usize> {
+        let from = self.branches.iter().position(|b| b.name == branch)?;
+        let to = (from as isize + delta).rem_euclid(self.branches.len() as isize) as usize;
+        let branch = self.branches.remove(from);
+        self.branches.insert(to, branch);
+        Some(to)
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 struct DB {
+    version: u32,
     projects: Vec<Project>,
 }
 
 impl DB {
     fn new() -> DB {
         DB {
+            version: DB_VERSION,
             projects: Vec::new(),
         }
     }
@@ -57,19 +201,62 @@ impl DB {
         self.projects.iter_mut().find(|p| p.path == path)
     }
 
-    pub fn write_to_disk(&self) -> Result<()> {
-        let mut file = std::fs::File::create(DB_PATH)?;
-        for project in &self.projects {
-            file.write_all(format!("{}{}\n", PROJECT_PATH_DELIMITER, project.path).as_bytes())?;
-            for branch in &project.branches {
-                file.write_all(format!("{}\n", branch.name).as_bytes())?;
-            }
+    // writes to a temporary sibling file and renames it over the db
+    // so readers never see a partially written file.
+    fn write_to(&self, path: &Path) -> Result<()> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let tmp_path = sibling_path(path, ".tmp");
+        let mut file = std::fs::File::create(&tmp_path)?;
+        file.write_all(self.serialize()?.as_bytes())?;
+        file.sync_all()?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    fn serialize(&self) -> Result<String> {
+        let mut out = serde_json::to_string_pretty(self)?;
+        out.push('\n');
+        Ok(out)
+    }
+
+    // copies the db from its old location in /etc the first time
+    // the new location is used. a marker next to the db records that
+    // it was done, so removing the db later doesn't bring the old one
+    // back. an existing db is never overwritten and the old file is
+    // left untouched.
+    fn migrate_legacy_db(legacy: &Path, path: &Path) -> Result<()> {
+        let marker = sibling_path(path, ".migrated");
+        if marker.exists() || !legacy.exists() {
+            return Ok(());
+        }
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        if !path.exists() {
+            std::fs::copy(legacy, path)?;
+            eprintln!(
+                "migrated database from {} to {}",
+                legacy.display(),
+                path.display()
+            );
+        }
+        std::fs::File::create(marker)?;
+        Ok(())
+    }
+
+    // only the default location is migrated to, the env var and the
+    // --db flag point elsewhere.
+    fn migrate_default_db(path: &Path) -> Result<()> {
+        if default_db_path(non_empty_env).is_ok_and(|default| default == path) {
+            DB::migrate_legacy_db(Path::new(LEGACY_DB_PATH), path)?;
         }
         Ok(())
     }
 
-    fn read_db_file() -> Result<String> {
-        let file = std::fs::read_to_string(DB_PATH);
+    fn read_db_file(path: &Path) -> Result<String> {
+        let file = std::fs::read_to_string(path);
         if let Err(e) = file {
             if e.kind() == std::io::ErrorKind::NotFound {
                 return Ok(String::new()); // if file is not found return empty and wait for write later
@@ -80,199 +267,2453 @@ impl DB {
         Ok(file.unwrap())
     }
 
-    pub fn load_from_disk() -> Result<Self> {
-        let file = DB::read_db_file()?;
-        let lines = file.lines();
-        let mut path = "";
+    fn load_from(path: &Path) -> Result<Self> {
+        let file = DB::read_db_file(path)?;
+        DB::parse(file.as_str())
+    }
+
+    // the structured format is a json object, anything else is
+    // treated as the legacy `;;;;` text format. either way the db
+    // is upgraded to the current version on the next write.
+    fn parse(contents: &str) -> Result<Self> {
+        let (db, issues) = DB::parse_lenient(contents)?;
+        let orphan = issues.into_iter().find_map(|issue| match issue {
+            DBIssue::OrphanLine { line, content } => Some(DBError::OrphanLine { line, content }),
+            _ => None,
+        });
+        match orphan {
+            Some(err) => Err(err.into()),
+            None => Ok(db),
+        }
+    }
+
+    // like `parse` but skips lines that don't belong to any project,
+    // returning them as issues instead of failing.
+    fn parse_lenient(contents: &str) -> Result<(Self, Vec<DBIssue>)> {
+        if contents.trim_start().starts_with('{') {
+            Ok((DB::parse_json(contents)?, Vec::new()))
+        } else {
+            Ok(DB::parse_legacy(contents))
+        }
+    }
+
+    fn parse_json(contents: &str) -> Result<Self> {
+        let mut db: DB = serde_json::from_str(contents).map_err(|e| DBError::InvalidJson {
+            line: e.line(),
+            column: e.column(),
+            message: e.to_string(),
+        })?;
+        if db.version > DB_VERSION {
+            return Err(DBError::UnsupportedVersion {
+                version: db.version,
+            }
+            .into());
+        }
+        db.version = DB_VERSION;
+        Ok(db)
+    }
+
+    fn parse_legacy(contents: &str) -> (Self, Vec<DBIssue>) {
         let mut db = DB::new();
-        for line in lines {
+        let mut issues = Vec::new();
+        for (ix, line) in contents.lines().enumerate() {
             if line.starts_with(PROJECT_PATH_DELIMITER) {
-                path = line.trim_start_matches(PROJECT_PATH_DELIMITER);
+                let path = line.trim_start_matches(PROJECT_PATH_DELIMITER);
                 db.add_project(Project::new(path.to_string()));
-            } else if !path.is_empty() {
-                let branch = line.to_string();
-                let project = db.get_project_mut(path);
-                if project.is_none() {
-                    panic!("Invalid file format");
+                continue;
+            }
+            match db.projects.last_mut() {
+                Some(project) if !project.path.is_empty() => {
+                    project.add_branch(Branch::new(line.to_string()));
                 }
-                project.unwrap().add_branch(branch);
-            } else {
-                panic!("Invalid file format");
+                _ => issues.push(DBIssue::OrphanLine {
+                    line: ix + 1,
+                    content: line.to_string(),
+                }),
             }
         }
-        Ok(db)
+        (db, issues)
+    }
+
+    // reports duplicated projects and branches, and projects whose
+    // directory no longer exists.
+    fn find_issues(&self) -> Vec<DBIssue> {
+        let mut issues = Vec::new();
+        let mut seen_projects: Vec<&str> = Vec::new();
+        for project in &self.projects {
+            if seen_projects.contains(&project.path.as_str()) {
+                issues.push(DBIssue::DuplicateProject {
+                    path: project.path.clone(),
+                });
+                continue;
+            }
+            seen_projects.push(project.path.as_str());
+            if !Path::new(project.path.as_str()).is_dir() {
+                issues.push(DBIssue::MissingProjectDir {
+                    path: project.path.clone(),
+                });
+            }
+            let mut seen_branches: Vec<&str> = Vec::new();
+            for branch in &project.branches {
+                if seen_branches.contains(&branch.name.as_str()) {
+                    issues.push(DBIssue::DuplicateBranch {
+                        path: project.path.clone(),
+                        branch: branch.name.clone(),
+                    });
+                } else {
+                    seen_branches.push(branch.name.as_str());
+                }
+            }
+        }
+        issues
+    }
+
+    // drops missing projects and merges duplicates, keeping the
+    // first occurrence of each project and branch. duplicated
+    // projects only add their branches to the first one.
+    fn repair(&mut self) {
+        let mut projects: Vec<Project> = Vec::new();
+        for mut project in self.projects.drain(..) {
+            if !Path::new(project.path.as_str()).is_dir() {
+                continue;
+            }
+            let branches = std::mem::take(&mut project.branches);
+            let target = match projects.iter_mut().find(|p| p.path == project.path) {
+                Some(existing) => existing,
+                None => {
+                    projects.push(project);
+                    projects.last_mut().unwrap()
+                }
+            };
+            for branch in branches {
+                if !target.branches.iter().any(|b| b.name == branch.name) {
+                    target.branches.push(branch);
+                }
+            }
+        }
+        self.projects = projects;
+    }
+}
+
+// advisory lock on a file next to the db, held for a whole
+// read-modify-write cycle. released when dropped.
+struct DBLock {
+    _file: std::fs::File,
+}
+
+impl DBLock {
+    fn acquire(db_path: &Path) -> Result<DBLock> {
+        if let Some(parent) = db_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(sibling_path(db_path, ".lock"))?;
+        file.lock()?;
+        Ok(DBLock { _file: file })
+    }
+}
+
+fn sibling_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+// loads the db, applies `f` and writes the result back while holding
+// the db lock, so concurrent processes don't overwrite each other.
+fn update_db_at<T>(path: &Path, f: impl FnOnce(&mut DB) -> Result<T>) -> Result<T> {
+    let _lock = DBLock::acquire(path)?;
+    let mut db = DB::load_from(path)?;
+    let res = f(&mut db)?;
+    db.write_to(path)?;
+    Ok(res)
+}
+
+/// why a db file couldn't be loaded
+#[derive(Debug)]
+pub enum DBError {
+    OrphanLine {
+        line: usize,
+        content: String,
+    },
+    InvalidJson {
+        line: usize,
+        column: usize,
+        message: String,
+    },
+    UnsupportedVersion {
+        version: u32,
+    },
+}
+
+impl std::fmt::Display for DBError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DBError::OrphanLine { line, content } => write!(
+                f,
+                "invalid database: line {line} ({content:?}) does not belong to any project, \
+                 run `lazy-git-checkout db repair` to remove it"
+            ),
+            DBError::InvalidJson {
+                line,
+                column,
+                message,
+            } => write!(
+                f,
+                "invalid database at line {line} column {column}: {message}"
+            ),
+            DBError::UnsupportedVersion { version } => write!(
+                f,
+                "database version {version} is newer than the supported version {DB_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DBError {}
+
+/// a problem found in the db by `DBHandle::check_at`
+#[derive(Debug, Clone, PartialEq)]
+pub enum DBIssue {
+    OrphanLine { line: usize, content: String },
+    DuplicateProject { path: String },
+    DuplicateBranch { path: String, branch: String },
+    MissingProjectDir { path: String },
+}
+
+impl std::fmt::Display for DBIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DBIssue::OrphanLine { line, content } => {
+                write!(f, "line {line}: {content:?} does not belong to any project")
+            }
+            DBIssue::DuplicateProject { path } => write!(f, "duplicated project {path}"),
+            DBIssue::DuplicateBranch { path, branch } => {
+                write!(f, "duplicated branch {branch} in project {path}")
+            }
+            DBIssue::MissingProjectDir { path } => {
+                write!(f, "project directory {path} does not exist")
+            }
+        }
+    }
+}
+
+/// the step a checkout was at, in the order they run
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum CheckoutStep {
+    Stash,
+    Checkout,
+    Pop,
+}
+
+/// progress of a running checkout, stored in the git dir while the
+/// checkout runs and removed once it finishes or is rolled back.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckoutJournal {
+    pub from_branch: String,
+    pub to_branch: String,
+    pub step: CheckoutStep,
+    // last stash before the checkout started, used to find out if
+    // the stash step ran when it was interrupted.
+    stash_before: Option<String>,
+    stashed: Option<String>,
+    popping: Option<String>,
+}
+
+impl std::fmt::Display for CheckoutJournal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let step = match self.step {
+            CheckoutStep::Stash => "stashing",
+            CheckoutStep::Checkout => "checking out",
+            CheckoutStep::Pop => "popping the stash",
+        };
+        write!(
+            f,
+            "checkout from {} to {} was interrupted while {step}",
+            self.from_branch, self.to_branch
+        )
+    }
+}
+
+/// how a branch's stash was restored
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PopMode {
+    WithIndex,
+    WithoutIndex,
+}
+
+impl std::fmt::Display for PopMode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PopMode::WithIndex => write!(f, "stash popped with --index, staged changes kept"),
+            PopMode::WithoutIndex => write!(
+                f,
+                "stash popped without --index, staged changes restored as unstaged"
+            ),
+        }
+    }
+}
+
+fn branch_stash_ref(branch: &str) -> String {
+    format!("{STASH_REF_PREFIX}{branch}")
+}
+
+/// returned by checkout when popping the target branch's stash
+/// conflicts. the files are left with conflict markers and the stash
+/// is kept until the conflict is resolved or aborted.
+#[derive(Debug, Clone)]
+pub struct StashConflict {
+    pub branch: String,
+    pub stash: String,
+    pub files: Vec<String>,
+}
+
+impl std::fmt::Display for StashConflict {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "popping the stash for {} conflicted in: {}",
+            self.branch,
+            self.files.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for StashConflict {}
+
+/// git operation left in progress in the repository. stashing and
+/// changing branches in the middle of one can break it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GitOperation {
+    Merge,
+    Rebase,
+    CherryPick,
+    Bisect,
+}
+
+impl std::fmt::Display for GitOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            GitOperation::Merge => write!(f, "merge"),
+            GitOperation::Rebase => write!(f, "rebase"),
+            GitOperation::CherryPick => write!(f, "cherry-pick"),
+            GitOperation::Bisect => write!(f, "bisect"),
+        }
+    }
+}
+
+/// returned by checkout when a git operation is in progress and the
+/// checkout isn't forced. nothing is stashed or checked out.
+#[derive(Debug, Clone)]
+pub struct OperationInProgress {
+    pub operation: GitOperation,
+}
+
+impl std::fmt::Display for OperationInProgress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "a {} is in progress, finish or abort it first or use --force to check out anyway",
+            self.operation
+        )
+    }
+}
+
+impl std::error::Error for OperationInProgress {}
+
+/// last commit and upstream tracking status of a branch
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchInfo {
+    pub subject: String,
+    pub author: String,
+    pub date: String,
+    pub upstream: Option<String>,
+    pub ahead: usize,
+    pub behind: usize,
+}
+
+/// where a `BranchRef` lives
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BranchKind {
+    Local,
+    Remote,
+    /// symbolic ref to the default branch of a remote, like origin/HEAD
+    Head,
+}
+
+/// a branch as found in the repository, unlike `Branch` which is a
+/// branch saved in the db.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BranchRef {
+    /// `main` for local branches, `origin/main` for remote ones
+    pub name: String,
+    pub kind: BranchKind,
+    pub upstream: Option<String>,
+    pub sha: String,
+    /// committer date of the tip as a unix timestamp
+    pub date: i64,
+}
+
+/// a branch of a remote, checked out through a local branch of the
+/// same name tracking it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RemoteBranch {
+    pub remote: String,
+    pub name: String,
+}
+
+impl RemoteBranch {
+    /// `<remote>/<branch>`
+    pub fn upstream(&self) -> String {
+        format!("{}/{}", self.remote, self.name)
+    }
+}
+
+/// a working tree of the repository, as listed by `git worktree list`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Worktree {
+    pub path: PathBuf,
+    pub head: String,
+    /// None when HEAD is detached
+    pub branch: Option<String>,
+    /// its directory is gone, pruning removes it
+    pub prunable: bool,
+}
+
+impl Worktree {
+    // reads the blocks printed by `git worktree list --porcelain`
+    fn parse_list(output: &str) -> Vec<Worktree> {
+        let mut worktrees = Vec::new();
+        for block in output.split("\n\n") {
+            let mut worktree = None;
+            for line in block.lines() {
+                let (key, value) = line.split_once(' ').unwrap_or((line, ""));
+                match (key, worktree.as_mut()) {
+                    ("worktree", _) => {
+                        worktree = Some(Worktree {
+                            path: PathBuf::from(value),
+                            head: String::new(),
+                            branch: None,
+                            prunable: false,
+                        })
+                    }
+                    ("HEAD", Some(w)) => w.head = value.to_string(),
+                    ("branch", Some(w)) => {
+                        w.branch = Some(value.trim_start_matches("refs/heads/").to_string())
+                    }
+                    ("prunable", Some(w)) => w.prunable = true,
+                    _ => {}
+                }
+            }
+            worktrees.extend(worktree);
+        }
+        worktrees
+    }
+}
+
+/// a stash created by lgc, as listed in the stash manager
+#[derive(Debug, Clone, PartialEq)]
+pub struct LgcStash {
+    pub sha: String,
+    pub branch: String,
+    pub label: String,
+    pub age: String,
+    pub files: usize,
+    /// whether it's the stash popped when checking out `branch`
+    pub tracked: bool,
+}
+
+impl LgcStash {
+    fn message(branch: &str, label: &str) -> String {
+        if label.is_empty() {
+            format!("{STASH_MESSAGE_PREFIX}{branch}")
+        } else {
+            format!("{STASH_MESSAGE_PREFIX}{branch} {label}")
+        }
+    }
+
+    // the reverse of `message`, returns the branch and label of a
+    // stash subject or None if lgc didn't make the stash.
+    fn parse_subject(subject: &str) -> Option<(&str, &str)> {
+        let (_, name) = subject.split_once(STASH_MESSAGE_PREFIX)?;
+        Some(name.split_once(' ').unwrap_or((name, "")))
     }
 }
 
+// whether `name` can be used in a ref, following the rules of
+// `git check-ref-format`.
+fn is_valid_ref_name(name: &str) -> bool {
+    !name.is_empty()
+        && name != "@"
+        && !name.ends_with('.')
+        && !name.contains("..")
+        && !name.contains("@{")
+        && !name
+            .chars()
+            .any(|c| c.is_ascii_control() || " ~^:?*[\\".contains(c))
+        && name
+            .split('/')
+            .all(|part| !part.is_empty() && !part.starts_with('.') && !part.ends_with(".lock"))
+}
+
+/// runs the lgc flows in the repository at `path`.
+///
+/// the backend is shared through an `Rc`, so a `Git` is neither `Send`
+/// nor `Sync` and has to stay on the thread that created it.
+#[derive(Clone)]
 pub struct Git {
     pub path: String,
+    backend: Rc<dyn GitBackend>,
 }
 
 impl Git {
+    /// uses the default backend, see `backend::default_backend`
     pub fn new(path: String) -> Git {
-        Git { path }
+        let backend = backend::default_backend(path.as_str());
+        Git { path, backend }
     }
 
-    pub fn checkout(&self, branch: &str) -> Result<()> {
-        let cur_branch = self.get_current_branch()?;
-        let stash_name = format!("lazy-git-checkout:{}", cur_branch);
-        println!("> stashing...");
-        self.stream_git_command(vec!["stash", "-m", stash_name.as_str()])?;
+    /// runs the git operations of the checkout flow through `backend`
+    pub fn with_backend(path: String, backend: Rc<dyn GitBackend>) -> Git {
+        Git { path, backend }
+    }
+
+    /// stashes the current changes, checks out `branch` and pops the
+    /// last stash made for it. if any step fails the previous steps
+    /// are rolled back so the working tree ends up where it started.
+    /// every step is recorded in a journal before running it so an
+    /// interrupted checkout can be finished or undone with `recover`.
+    pub fn checkout(&self, branch: &str, opts: &CheckoutOptions) -> Result<()> {
+        if let Some(journal) = self.interrupted_checkout()? {
+            bail!("{journal}, run `lazy-git-checkout recover` to finish or undo it");
+        }
+        if let Some(operation) = self.operation_in_progress()? {
+            if !opts.force {
+                return Err(OperationInProgress { operation }.into());
+            }
+            println!("> a {operation} is in progress, checking out anyway");
+        }
+        // before stashing, or the new stash would be adopted as legacy
+        self.adopt_legacy_stashes()?;
+        let branch = self.local_branch_for(branch)?;
+        let branch = branch.as_str();
+        let mut journal = CheckoutJournal {
+            from_branch: self.current_stash_key()?,
+            to_branch: branch.to_string(),
+            step: CheckoutStep::Stash,
+            stash_before: self.last_stash_sha()?,
+            stashed: None,
+            popping: None,
+        };
+        self.write_journal(&journal)?;
+        if let Err(err) = self.stash_step(&mut journal, opts.stash_mode) {
+            return Err(self.rollback(err, &journal));
+        }
+        journal.step = CheckoutStep::Checkout;
+        self.write_journal(&journal)?;
         println!("> checkout...");
-        self.stream_git_command(vec!["checkout", branch])?;
-        let last_stashed = self.get_last_stashed(branch);
-        if let Some(last_stashed) = last_stashed {
+        if let Err(err) = self.backend.checkout(branch) {
+            return Err(self.rollback(err, &journal));
+        }
+        let to_key = self.target_stash_key(branch)?;
+        if let Some(sha) = self.branch_stash(to_key.as_str())? {
+            journal.popping = Some(sha.clone());
+            journal.step = CheckoutStep::Pop;
+            self.write_journal(&journal)?;
             println!("> popping stash...");
-            self.stream_git_command(vec!["stash", "pop", last_stashed.as_ref()])?;
+            match self.pop_branch_stash(to_key.as_str(), sha.as_str()) {
+                Ok(mode) => println!("> {mode}"),
+                Err(err) => match self.stash_conflict(to_key.as_str(), sha.as_str())? {
+                    Some(conflict) => {
+                        self.clear_journal()?;
+                        return Err(conflict.into());
+                    }
+                    None => return Err(self.rollback(err, &journal)),
+                },
+            }
         }
-        Ok(())
+        self.clear_journal()
     }
 
-    pub fn all_project_branches(&self) -> Result<Vec<String>> {
-        let output = self.run_git_command(vec!["branch", "-a"])?;
-        let branches = String::from_utf8(output.stdout)?;
-        let branches = branches.split('\n');
-        let branches = branches
-            .map(|b| b.trim())
-            .filter(|b| !b.is_empty())
-            .map(|b| b.trim_start_matches('*'))
-            .map(|b| b.trim())
-            .map(|b| b.to_string())
-            .collect::<Vec<String>>();
-        Ok(branches)
+    // stashes the changes of the branch being left and saves the
+    // stash for it, recording its sha in the journal.
+    fn stash_step(&self, journal: &mut CheckoutJournal, mode: StashMode) -> Result<()> {
+        let from = journal.from_branch.clone();
+        println!("> stashing...");
+        let stash_name = format!("{STASH_MESSAGE_PREFIX}{from}");
+        journal.stashed = self.stash(stash_name.as_str(), mode)?;
+        // leaving the branch clean keeps the stash still pending for it
+        let Some(sha) = &journal.stashed else {
+            return Ok(());
+        };
+        if let Some(old) = self.branch_stash(from.as_str())? {
+            println!("> an older stash for {from} ({old}) is left in the stash list");
+        }
+        self.save_branch_stash(from.as_str(), sha.as_str())
     }
 
-    pub fn get_current_branch(&self) -> Result<String> {
-        let output = self.run_git_command(vec!["rev-parse", "--abbrev-ref", "HEAD"])?;
-        let branch = String::from_utf8(output.stdout)?;
-        Ok(branch.trim().to_string())
+    // stashes the working tree and returns the sha of the new stash,
+    // or None if there was nothing to stash.
+    fn stash(&self, message: &str, mode: StashMode) -> Result<Option<String>> {
+        let sha = self.backend.stash(message, mode)?;
+        if sha.is_none() {
+            println!("> nothing to stash");
+        }
+        Ok(sha)
     }
 
-    fn run_git_command(&self, command: Vec<&str>) -> Result<Output> {
-        let output = std::process::Command::new("git")
-            .args(command)
-            .current_dir(self.path.as_str())
-            .output()?;
-        if !output.status.success() {
-            let error = String::from_utf8(output.stderr)?;
-            return Err(anyhow::anyhow!(error));
+    // undoes a failed checkout and returns the error to report,
+    // the journal is kept if the rollback fails too.
+    fn rollback(&self, err: anyhow::Error, journal: &CheckoutJournal) -> anyhow::Error {
+        println!("> rolling back...");
+        match self.undo_steps(journal).and_then(|_| self.clear_journal()) {
+            Ok(()) => err.context("checkout failed, all changes were rolled back"),
+            Err(rollback_err) => anyhow!(
+                "checkout failed: {err}\nrollback failed: {rollback_err}\n\
+                 run `lazy-git-checkout recover` once the problem is fixed"
+            ),
         }
-        Ok(output)
     }
 
-    fn stream_git_command(&self, command: Vec<&str>) -> Result<()> {
-        std::process::Command::new("git")
-            .args(command)
-            .current_dir(self.path.as_str())
-            .spawn()?
-            .wait()?;
+    // if the branch was already changed the tree is reset and the
+    // previous branch checked out again, then the stash created at
+    // the start is re-applied along with its index.
+    fn undo_steps(&self, journal: &CheckoutJournal) -> Result<()> {
+        if journal.step == CheckoutStep::Pop {
+            self.restash_popped(journal)?;
+        }
+        if journal.step == CheckoutStep::Pop || self.current_stash_key()? != journal.from_branch {
+            self.stream_git_command(vec!["reset", "--hard", "HEAD"])?;
+            self.backend.checkout(journal.from_branch.as_str())?;
+        }
+        if let Some(sha) = self.journal_stash(journal)? {
+            self.backend.stash_pop(sha.as_str(), true)?;
+            self.clear_branch_stash(journal.from_branch.as_str())?;
+        }
         Ok(())
     }
 
-    fn get_last_stashed(&self, branch: &str) -> Option<String> {
-        let output = self.run_git_command(vec!["stash", "list"]).unwrap();
-        let stash_name = format!("lazy-git-checkout:{}", branch);
-        let matched = String::from_utf8(output.stdout)
-            .ok()?
-            .split('\n')
-            .filter(|s| s.ends_with(stash_name.as_str()))
-            .collect::<Vec<&str>>()
-            .first()?
-            .split(':')
-            .collect::<Vec<&str>>()
-            .first()?
-            .to_string();
-        Some(matched)
+    // a pop that finished before the checkout was interrupted already
+    // dropped the target's stash, so the changes it restored are
+    // stashed for the target again instead of being reset away.
+    fn restash_popped(&self, journal: &CheckoutJournal) -> Result<()> {
+        let Some(popped) = &journal.popping else {
+            return Ok(());
+        };
+        if self.stash_exists(popped.as_str()) {
+            return Ok(());
+        }
+        // the popped commit is still around, its third parent holds
+        // the untracked files it was stashed with
+        let untracked = self
+            .backend
+            .resolve_commit(format!("{popped}^3").as_str())?;
+        let mode = match untracked {
+            Some(_) => StashMode::IncludeUntracked,
+            None => StashMode::Tracked,
+        };
+        let to_key = self.target_stash_key(journal.to_branch.as_str())?;
+        println!("> stashing the changes popped for {to_key} again...");
+        let message = format!("{STASH_MESSAGE_PREFIX}{to_key}");
+        match self.stash(message.as_str(), mode)? {
+            Some(sha) => self.save_branch_stash(to_key.as_str(), sha.as_str()),
+            None => self.clear_branch_stash(to_key.as_str()),
+        }
     }
-}
 
-pub fn add_project(path: &str) -> Result<()> {
-    let mut db = DB::load_from_disk()?;
-    db.add_project(Project::new(path.to_string()));
-    db.write_to_disk()?;
-    Ok(())
-}
+    // returns the stash created by the journaled checkout, also when
+    // it was interrupted before the sha could be recorded.
+    fn journal_stash(&self, journal: &CheckoutJournal) -> Result<Option<String>> {
+        if journal.stashed.is_some() || journal.step != CheckoutStep::Stash {
+            return Ok(journal.stashed.clone());
+        }
+        let last = self.last_stash_sha()?;
+        if last == journal.stash_before {
+            return Ok(None);
+        }
+        Ok(last)
+    }
 
-pub fn remove_project(path: &str) -> Result<()> {
-    let mut db = DB::load_from_disk()?;
-    db.remove_project(path);
-    db.write_to_disk()?;
-    Ok(())
-}
+    /// the journal of a checkout that didn't finish, if any
+    pub fn interrupted_checkout(&self) -> Result<Option<CheckoutJournal>> {
+        let contents = match std::fs::read_to_string(self.journal_path()?) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(anyhow!(e)),
+        };
+        Ok(Some(serde_json::from_str(contents.as_str())?))
+    }
 
-pub fn add_branch(path: &str, branch: String) -> Result<()> {
-    let mut db = DB::load_from_disk()?;
-    db.get_project_mut(path)
-        .ok_or(anyhow!("no project found"))?
-        .add_branch(branch);
-    db.write_to_disk()?;
-    Ok(())
-}
+    /// runs the remaining steps of an interrupted checkout, starting
+    /// from the one it was interrupted at.
+    pub fn finish_checkout(&self, journal: &CheckoutJournal, opts: &CheckoutOptions) -> Result<()> {
+        let mut journal = journal.clone();
+        self.adopt_legacy_stashes()?;
+        match self.journal_stash(&journal)? {
+            Some(sha) => self.save_branch_stash(journal.from_branch.as_str(), sha.as_str())?,
+            None if journal.step == CheckoutStep::Stash => {
+                self.stash_step(&mut journal, opts.stash_mode)?
+            }
+            None => {}
+        }
+        let to_key = self.target_stash_key(journal.to_branch.as_str())?;
+        if self.current_stash_key()? != to_key {
+            println!("> checkout...");
+            self.backend.checkout(journal.to_branch.as_str())?;
+        }
+        let target = match &journal.popping {
+            Some(sha) => Some(sha.clone()).filter(|sha| self.stash_exists(sha.as_str())),
+            None => self.branch_stash(to_key.as_str())?,
+        };
+        if let Some(sha) = target {
+            println!("> popping stash...");
+            match self.pop_branch_stash(to_key.as_str(), sha.as_str()) {
+                Ok(mode) => println!("> {mode}"),
+                Err(err) => {
+                    let conflict = self.stash_conflict(to_key.as_str(), sha.as_str())?;
+                    self.clear_journal()?;
+                    return Err(conflict.map(anyhow::Error::from).unwrap_or(err));
+                }
+            }
+        }
+        self.clear_journal()
+    }
 
-pub fn remove_branch(path: &str, branch: String) -> Result<()> {
-    let mut db = DB::load_from_disk()?;
-    db.get_project_mut(path)
-        .ok_or(anyhow!("no project found"))?
-        .remove_branch(branch);
-    db.write_to_disk()?;
-    Ok(())
-}
+    /// looks for the files git keeps in the git dir while an
+    /// operation is stopped waiting for the user.
+    pub fn operation_in_progress(&self) -> Result<Option<GitOperation>> {
+        let git_dir = self.backend.git_dir()?;
+        let markers = [
+            ("MERGE_HEAD", GitOperation::Merge),
+            ("rebase-merge", GitOperation::Rebase),
+            ("rebase-apply", GitOperation::Rebase),
+            ("CHERRY_PICK_HEAD", GitOperation::CherryPick),
+            ("BISECT_LOG", GitOperation::Bisect),
+        ];
+        let operation = markers
+            .into_iter()
+            .find(|(marker, _)| git_dir.join(marker).exists())
+            .map(|(_, operation)| operation);
+        Ok(operation)
+    }
 
-pub fn get_branches(path: &str) -> Result<Vec<Branch>> {
-    let db = DB::load_from_disk()?;
-    let project = db.projects.iter().find(|p| path == p.path.as_str());
-    if let Some(project) = project {
-        Ok(project.branches.clone())
-    } else {
-        Err(anyhow!("no project found in path"))
+    // returns the conflict left by popping `sha`, if any. git keeps
+    // the stash in the list when popping it conflicts.
+    fn stash_conflict(&self, branch: &str, sha: &str) -> Result<Option<StashConflict>> {
+        let files = self.conflicted_files()?;
+        if files.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(StashConflict {
+            branch: branch.to_string(),
+            stash: sha.to_string(),
+            files,
+        }))
+    }
+
+    /// paths relative to the repository root
+    pub fn conflicted_files(&self) -> Result<Vec<String>> {
+        let output = self.run_git_command(vec!["diff", "--name-only", "--diff-filter=U"])?;
+        let files = String::from_utf8(output.stdout)?
+            .lines()
+            .map(|f| f.to_string())
+            .collect();
+        Ok(files)
+    }
+
+    /// the main worktree comes first
+    pub fn worktrees(&self) -> Result<Vec<Worktree>> {
+        let output = self.run_git_command(vec!["worktree", "list", "--porcelain"])?;
+        Ok(Worktree::parse_list(
+            String::from_utf8(output.stdout)?.as_str(),
+        ))
     }
-}
 
-pub fn set_branches(path: &str, branches: Vec<&str>) -> Result<()> {
-    let mut db = DB::load_from_disk()?;
-    let project = db.projects.iter_mut().find(|p| path == p.path.as_str());
-    if let Some(project) = project {
-        project.branches = branches
+    /// returns the worktree `branch` is checked out in, adding one if
+    /// there is none. worktrees are added next to the main one, in
+    /// `<project>.worktrees/<branch>`.
+    pub fn branch_worktree(&self, branch: &str) -> Result<PathBuf> {
+        let worktrees = self.worktrees()?;
+        let mut found = worktrees
             .iter()
-            .map(|b| Branch {
-                name: b.to_string(),
-            })
-            .collect::<Vec<Branch>>();
-    } else {
-        return Err(anyhow!("no project found in path"));
-    }
-    db.write_to_disk()?;
-    Ok(())
-}
-
-pub fn list_projects() -> Result<()> {
-    let db = DB::load_from_disk()?;
-    for project in &db.projects {
-        println!("{}", project.path);
-        for branch in &project.branches {
-            println!("  {}", branch.name);
-        }
-    }
-    Ok(())
-}
-
-// returns the first project that matches with the path.
-// the path passed can be a subdirectory of a projects path.
-// for example:
-// if the project path is /home/user/project
-// and the path passed is /home/user/project/src/mod/a/b/c
-// the project will be returned.
-pub fn get_project_from_path(path: &std::path::Path) -> Result<Project> {
-    let db = DB::load_from_disk()?;
-    let path = path.canonicalize()?;
-    let path = path.to_str().unwrap();
-    let project = db
-        .projects
-        .iter()
-        .find(|p| path.starts_with(p.path.as_str()));
-    if let Some(proj) = project {
-        Ok(proj.clone())
-    } else {
-        Err(anyhow!("no project found in path"))
+            .filter(|w| w.branch.as_deref() == Some(branch));
+        if let Some(worktree) = found.clone().find(|w| !w.prunable) {
+            return Ok(worktree.path.clone());
+        }
+        // git refuses to add a worktree for a branch still registered
+        // in one whose directory was removed
+        if found.any(|w| w.prunable) {
+            self.prune_worktrees()?;
+        }
+        let main = worktrees.first().ok_or(anyhow!("no worktrees found"))?;
+        let name = main
+            .path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or(anyhow!("bad path"))?;
+        let path = main
+            .path
+            .with_file_name(format!("{name}.worktrees"))
+            .join(branch);
+        let path_str = path.to_str().ok_or(anyhow!("bad path"))?;
+        self.run_git_command(vec!["worktree", "add", path_str, branch])?;
+        println!("> created worktree for {branch} at {path_str}");
+        Ok(path)
+    }
+
+    /// forgets the worktrees whose directory was removed
+    pub fn prune_worktrees(&self) -> Result<()> {
+        self.stream_git_command(vec!["worktree", "prune", "--verbose"])
+    }
+
+    /// root directory of the working tree
+    pub fn toplevel(&self) -> Result<PathBuf> {
+        let output = self.run_git_command(vec!["rev-parse", "--show-toplevel"])?;
+        Ok(PathBuf::from(String::from_utf8(output.stdout)?.trim()))
+    }
+
+    /// discards the partially popped stash, leaving the branch as it
+    /// was right after the checkout. the stash is kept for later.
+    pub fn abort_conflict(&self) -> Result<()> {
+        self.run_git_command(vec!["reset", "--hard", "HEAD"])?;
+        Ok(())
+    }
+
+    /// unstages the merged changes and drops the stash, refusing to
+    /// do so while conflict markers are left in any of the files.
+    pub fn resolve_conflict(&self, conflict: &StashConflict) -> Result<()> {
+        let toplevel = self.toplevel()?;
+        for file in &conflict.files {
+            let contents = std::fs::read_to_string(toplevel.join(file)).unwrap_or_default();
+            if contents
+                .lines()
+                .any(|l| l.starts_with("<<<<<<< ") || l.starts_with(">>>>>>> "))
+            {
+                bail!("{file} still has conflict markers");
+            }
+        }
+        self.run_git_command(vec!["reset", "-q"])?;
+        if self.stash_exists(conflict.stash.as_str()) {
+            let stash_ref = self.find_stash_ref(conflict.stash.as_str())?;
+            self.run_git_command(vec!["stash", "drop", stash_ref.as_str()])?;
+        }
+        self.clear_branch_stash(conflict.branch.as_str())
+    }
+
+    /// restores the state from before an interrupted checkout
+    pub fn undo_checkout(&self, journal: &CheckoutJournal) -> Result<()> {
+        self.undo_steps(journal)?;
+        self.clear_journal()
+    }
+
+    fn journal_path(&self) -> Result<PathBuf> {
+        Ok(self.backend.git_dir()?.join(JOURNAL_FILE_NAME))
+    }
+
+    fn write_journal(&self, journal: &CheckoutJournal) -> Result<()> {
+        let path = self.journal_path()?;
+        let tmp_path = sibling_path(&path, ".tmp");
+        std::fs::write(&tmp_path, serde_json::to_string_pretty(journal)?)?;
+        std::fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+
+    fn clear_journal(&self) -> Result<()> {
+        match std::fs::remove_file(self.journal_path()?) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(anyhow!(e)),
+            _ => Ok(()),
+        }
+    }
+
+    // pops the stash saved for `branch`, see `restore_stash_changes`
+    fn pop_branch_stash(&self, branch: &str, sha: &str) -> Result<PopMode> {
+        let mode = self.pop_stash(sha)?;
+        self.clear_branch_stash(branch)?;
+        Ok(mode)
+    }
+
+    fn pop_stash(&self, sha: &str) -> Result<PopMode> {
+        self.restore_stash_changes(|index| self.backend.stash_pop(sha, index))
+    }
+
+    // pops or applies a stash through `restore`, keeping the
+    // staged/unstaged split. if the index can't be restored and git
+    // left the tree untouched, restores again without --index so the
+    // changes end up unstaged.
+    fn restore_stash_changes(&self, restore: impl Fn(bool) -> Result<()>) -> Result<PopMode> {
+        let status_before = self.status()?;
+        let err = match restore(true) {
+            Ok(()) => return Ok(PopMode::WithIndex),
+            Err(err) => err,
+        };
+        if self.status()? != status_before {
+            return Err(err);
+        }
+        restore(false)?;
+        Ok(PopMode::WithoutIndex)
+    }
+
+    fn status(&self) -> Result<String> {
+        self.backend.status()
+    }
+
+    /// whether there are changes or untracked files in the working tree
+    pub fn has_changes(&self) -> Result<bool> {
+        Ok(!self.status()?.trim().is_empty())
+    }
+
+    fn last_stash_sha(&self) -> Result<Option<String>> {
+        Ok(self.stash_list()?.into_iter().next().map(|(sha, _)| sha))
+    }
+
+    /// lists the stashes created by lgc, newest first. their message
+    /// is `lazy-git-checkout:<branch>` optionally followed by a label.
+    pub fn lgc_stashes(&self) -> Result<Vec<LgcStash>> {
+        let output = self.run_git_command(vec!["stash", "list", "--format=%H%x00%cr%x00%gs"])?;
+        let mut stashes = Vec::new();
+        for line in String::from_utf8(output.stdout)?.lines() {
+            let mut parts = line.splitn(3, '\0');
+            let (Some(sha), Some(age), Some(subject)) = (parts.next(), parts.next(), parts.next())
+            else {
+                continue;
+            };
+            let Some((branch, label)) = LgcStash::parse_subject(subject) else {
+                continue;
+            };
+            let tracked = self.read_ref(branch_stash_ref(branch).as_str())?.as_deref() == Some(sha);
+            stashes.push(LgcStash {
+                sha: sha.to_string(),
+                branch: branch.to_string(),
+                label: label.to_string(),
+                age: age.to_string(),
+                files: self.stash_file_count(sha)?,
+                tracked,
+            });
+        }
+        Ok(stashes)
+    }
+
+    /// returns the patch of the stash that would be popped when
+    /// checking out `branch`, including untracked files. only reads
+    /// the repo, so it's safe to call while drawing.
+    pub fn branch_stash_diff(&self, branch: &str) -> Result<Option<String>> {
+        let key = self.target_stash_key(branch)?;
+        let Some(sha) = self.pending_stash(key.as_str())? else {
+            return Ok(None);
+        };
+        let output = self.run_git_command(vec![
+            "stash",
+            "show",
+            "-p",
+            "--include-untracked",
+            sha.as_str(),
+        ])?;
+        Ok(Some(String::from_utf8_lossy(&output.stdout).into_owned()))
+    }
+
+    fn stash_file_count(&self, sha: &str) -> Result<usize> {
+        let output = self.run_git_command(vec![
+            "stash",
+            "show",
+            "--include-untracked",
+            "--name-only",
+            sha,
+        ])?;
+        Ok(String::from_utf8(output.stdout)?.lines().count())
+    }
+
+    /// applies the stash keeping it in the list, restoring the index
+    /// when possible like a checkout does.
+    pub fn apply_stash(&self, stash: &LgcStash) -> Result<()> {
+        self.restore_stash_changes(|index| {
+            let stash_ref = self.find_stash_ref(stash.sha.as_str())?;
+            let mut command = vec!["stash", "apply"];
+            if index {
+                command.push("--index");
+            }
+            command.push(stash_ref.as_str());
+            self.run_git_command(command).map(|_| ())
+        })?;
+        Ok(())
+    }
+
+    /// applies the stash and drops it
+    pub fn pop_lgc_stash(&self, stash: &LgcStash) -> Result<()> {
+        self.apply_stash(stash)?;
+        self.drop_stash(stash)
+    }
+
+    /// drops the stash and stops tracking it for its branch
+    pub fn drop_stash(&self, stash: &LgcStash) -> Result<()> {
+        let stash_ref = self.find_stash_ref(stash.sha.as_str())?;
+        self.run_git_command(vec!["stash", "drop", stash_ref.as_str()])?;
+        self.untrack_stash(stash)
+    }
+
+    /// sets the label shown after the branch in the stash message
+    pub fn rename_stash(&self, stash: &LgcStash, label: &str) -> Result<()> {
+        self.restore_stash(
+            stash,
+            LgcStash::message(stash.branch.as_str(), label.trim()),
+        )
+    }
+
+    /// makes the stash belong to `branch`, so it's the one popped the
+    /// next time `branch` is checked out. `branch` can also be one of
+    /// the saved tags or commits in `targets`. refuses to replace the
+    /// stash `branch` already has, which would be left untracked.
+    pub fn move_stash(&self, stash: &LgcStash, branch: &str, targets: &[Branch]) -> Result<()> {
+        let branch = branch.trim();
+        if branch.is_empty() {
+            bail!("no branch given");
+        }
+        let key = self.move_target_key(branch, targets)?;
+        let branch = key.as_str();
+        if let Some(sha) = self.branch_stash(branch)? {
+            if sha != stash.sha {
+                bail!("{branch} already has a stash, pop, drop or move it first");
+            }
+        }
+        self.restore_stash(stash, LgcStash::message(branch, stash.label.as_str()))?;
+        self.untrack_stash(stash)?;
+        self.save_branch_stash(branch, stash.sha.as_str())
+    }
+
+    // stash key of a local branch, or of a saved tag or commit given
+    // by its name or by the commit it points to. anything else would
+    // get a stash no checkout ever pops.
+    fn move_target_key(&self, name: &str, targets: &[Branch]) -> Result<String> {
+        if self.local_branch_exists(name)? {
+            return Ok(name.to_string());
+        }
+        for target in targets.iter().filter(|t| t.kind != TargetKind::Branch) {
+            let key = self.target_stash_key(target.name.as_str())?;
+            if target.name == name || key == name {
+                return Ok(key);
+            }
+        }
+        bail!("{name} is not a branch or a saved tag or commit")
+    }
+
+    // git can't edit a stash message, so the same commit is stored
+    // again with the new message and then the old entry dropped. it
+    // moved down one place once the new one was stored on top.
+    fn restore_stash(&self, stash: &LgcStash, message: String) -> Result<()> {
+        let sha = stash.sha.as_str();
+        let ix = self.stash_index(sha)?;
+        let count = self.stash_list()?.len();
+        self.store_stash(sha, message.as_str())?;
+        if self.stash_list()?.len() > count {
+            let old_ref = format!("stash@{{{}}}", ix + 1);
+            self.run_git_command(vec!["stash", "drop", old_ref.as_str()])?;
+            return Ok(());
+        }
+        // git doesn't store the newest stash on top of itself, so it
+        // has to be dropped first
+        self.run_git_command(vec!["stash", "drop", "stash@{0}"])?;
+        self.store_stash(sha, message.as_str()).map_err(|err| {
+            err.context(format!(
+                "the stash was dropped, restore it with `git stash store {sha}`"
+            ))
+        })
+    }
+
+    fn store_stash(&self, sha: &str, message: &str) -> Result<()> {
+        self.run_git_command(vec!["stash", "store", "-m", message, sha])?;
+        Ok(())
+    }
+
+    // removes the branch ref if it points to this stash
+    fn untrack_stash(&self, stash: &LgcStash) -> Result<()> {
+        let stash_ref = branch_stash_ref(stash.branch.as_str());
+        if self.read_ref(stash_ref.as_str())?.as_deref() == Some(stash.sha.as_str()) {
+            self.backend.delete_ref(stash_ref.as_str())?;
+        }
+        Ok(())
+    }
+
+    // returns (sha, subject) for every entry in the stash list
+    fn stash_list(&self) -> Result<Vec<(String, String)>> {
+        self.backend.stash_list()
+    }
+
+    fn stash_exists(&self, sha: &str) -> bool {
+        self.find_stash_ref(sha).is_ok()
+    }
+
+    fn find_stash_ref(&self, sha: &str) -> Result<String> {
+        backend::stash_ref(&self.stash_list()?, sha)
+    }
+
+    fn stash_index(&self, sha: &str) -> Result<usize> {
+        backend::stash_index(&self.stash_list()?, sha)
+    }
+
+    // returns the sha of the stash lgc saved the last time it left
+    // `branch`. the ref is the only link between a branch and its
+    // stash, so leaving a branch clean means nothing is popped when
+    // coming back, even if older lgc stashes for it are still around.
+    fn branch_stash(&self, branch: &str) -> Result<Option<String>> {
+        if let Some(sha) = self.pending_stash(branch)? {
+            return Ok(Some(sha));
+        }
+        // the stash may have been dropped outside of lgc
+        self.clear_branch_stash(branch)?;
+        Ok(None)
+    }
+
+    // like `branch_stash` without clearing a ref left by a stash
+    // dropped outside of lgc.
+    fn pending_stash(&self, branch: &str) -> Result<Option<String>> {
+        let sha = self.read_ref(branch_stash_ref(branch).as_str())?;
+        Ok(sha.filter(|sha| self.stash_exists(sha.as_str())))
+    }
+
+    // stashes made before they were tracked by ref only have their
+    // `lazy-git-checkout:<branch>` message. the first time lgc checks
+    // out in the repo the newest one of each branch is tracked like
+    // the ones lgc makes now, any other is stale and never popped.
+    fn adopt_legacy_stashes(&self) -> Result<()> {
+        if self.read_ref(LEGACY_STASHES_REF)?.is_some() {
+            return Ok(());
+        }
+        let Some(head) = self.backend.resolve_commit("HEAD")? else {
+            return Ok(());
+        };
+        let mut seen: Vec<String> = Vec::new();
+        for (sha, subject) in self.stash_list()? {
+            let Some((branch, _)) = LgcStash::parse_subject(subject.as_str()) else {
+                continue;
+            };
+            if seen.iter().any(|b| b == branch) {
+                continue;
+            }
+            // hand-written messages can hold names git can't store
+            if !is_valid_ref_name(branch) {
+                println!("> skipping stash {sha}, {branch} is not a valid branch name");
+                continue;
+            }
+            seen.push(branch.to_string());
+            if self.read_ref(branch_stash_ref(branch).as_str())?.is_none() {
+                println!("> tracking stash {sha} for {branch}");
+                self.save_branch_stash(branch, sha.as_str())?;
+            }
+        }
+        self.backend.update_ref(LEGACY_STASHES_REF, head.as_str())
+    }
+
+    // name the stash of what's checked out is saved under: the
+    // branch name, or the commit sha when HEAD is detached.
+    fn current_stash_key(&self) -> Result<String> {
+        let branch = self.get_current_branch()?;
+        if branch != "HEAD" {
+            return Ok(branch);
+        }
+        self.backend
+            .resolve_commit("HEAD")?
+            .ok_or(anyhow!("HEAD does not point to a commit"))
+    }
+
+    // like `current_stash_key` once `target` is checked out. tags and
+    // commits are checked out detached so they share the stash of
+    // the commit they point to.
+    fn target_stash_key(&self, target: &str) -> Result<String> {
+        if self.local_branch_exists(target)? {
+            return Ok(target.to_string());
+        }
+        Ok(self
+            .backend
+            .resolve_commit(target)?
+            .unwrap_or(target.to_string()))
+    }
+
+    /// names of the saved `targets` that are checked out. more than one
+    /// tag or commit can point to the commit HEAD is detached at.
+    pub fn checked_out(&self, targets: &[Branch]) -> Result<Vec<String>> {
+        let current = self.current_stash_key()?;
+        let mut checked_out = Vec::new();
+        for target in targets {
+            let key = match target.kind {
+                TargetKind::Branch => target.name.clone(),
+                TargetKind::Tag | TargetKind::Commit => {
+                    self.target_stash_key(target.name.as_str())?
+                }
+            };
+            if key == current {
+                checked_out.push(target.name.clone());
+            }
+        }
+        Ok(checked_out)
+    }
+
+    /// finds out whether `name` is a branch, tag or commit to save it
+    /// in the db. commits are saved by their full sha.
+    pub fn checkout_target(&self, name: &str) -> Result<Branch> {
+        if self.local_branch_exists(name)? {
+            return Ok(Branch::new(name.to_string()));
+        }
+        if self
+            .read_ref(format!("refs/tags/{name}").as_str())?
+            .is_some()
+        {
+            return Ok(Branch {
+                kind: TargetKind::Tag,
+                ..Branch::new(name.to_string())
+            });
+        }
+        match self.backend.resolve_commit(name)? {
+            Some(sha) => Ok(Branch {
+                kind: TargetKind::Commit,
+                ..Branch::new(sha)
+            }),
+            None => Ok(Branch::new(name.to_string())),
+        }
+    }
+
+    fn save_branch_stash(&self, branch: &str, sha: &str) -> Result<()> {
+        self.backend
+            .update_ref(branch_stash_ref(branch).as_str(), sha)
+    }
+
+    fn clear_branch_stash(&self, branch: &str) -> Result<()> {
+        let stash_ref = branch_stash_ref(branch);
+        if self.read_ref(stash_ref.as_str())?.is_some() {
+            self.backend.delete_ref(stash_ref.as_str())?;
+        }
+        Ok(())
+    }
+
+    fn read_ref(&self, name: &str) -> Result<Option<String>> {
+        self.backend.read_ref(name)
+    }
+
+    /// local branches followed by remote ones, each sorted by name
+    pub fn all_project_branches(&self) -> Result<Vec<BranchRef>> {
+        self.backend.branches()
+    }
+
+    /// recognizes `remotes/<remote>/<branch>` and `<remote>/<branch>`
+    /// as remote branches, local branches with those names win.
+    pub fn remote_branch(&self, name: &str) -> Result<Option<RemoteBranch>> {
+        let full_name = match name.strip_prefix("remotes/") {
+            Some(full_name) => full_name,
+            None if self.local_branch_exists(name)? => return Ok(None),
+            None => name,
+        };
+        let Some((remote, branch)) = full_name.split_once('/') else {
+            return Ok(None);
+        };
+        if self
+            .read_ref(format!("refs/remotes/{full_name}").as_str())?
+            .is_none()
+        {
+            return Ok(None);
+        }
+        Ok(Some(RemoteBranch {
+            remote: remote.to_string(),
+            name: branch.to_string(),
+        }))
+    }
+
+    /// whether `refs/heads/<name>` exists
+    pub fn local_branch_exists(&self, name: &str) -> Result<bool> {
+        Ok(self
+            .read_ref(format!("refs/heads/{name}").as_str())?
+            .is_some())
+    }
+
+    /// returns the local branch tracking `remote`, creating it first
+    /// if there is none with its name.
+    pub fn track_remote_branch(&self, remote: &RemoteBranch) -> Result<String> {
+        if !self.local_branch_exists(remote.name.as_str())? {
+            let upstream = remote.upstream();
+            self.backend
+                .create_branch(remote.name.as_str(), upstream.as_str())?;
+            println!("> created branch {} tracking {upstream}", remote.name);
+        }
+        Ok(remote.name.clone())
+    }
+
+    // remote branches are checked out through their local branch
+    // instead of detaching HEAD.
+    fn local_branch_for(&self, branch: &str) -> Result<String> {
+        match self.remote_branch(branch)? {
+            Some(remote) => self.track_remote_branch(&remote),
+            None => Ok(branch.to_string()),
+        }
+    }
+
+    /// last commit and upstream tracking status of `branch`
+    pub fn branch_info(&self, branch: &str) -> Result<BranchInfo> {
+        self.backend.branch_info(branch)
+    }
+
+    /// name of the checked out branch, `HEAD` when detached
+    pub fn get_current_branch(&self) -> Result<String> {
+        self.backend.current_branch()
+    }
+
+    fn run_git_command(&self, command: Vec<&str>) -> Result<Output> {
+        backend::run_git_command(self.path.as_str(), command)
+    }
+
+    fn stream_git_command(&self, command: Vec<&str>) -> Result<()> {
+        backend::stream_git_command(self.path.as_str(), command)
+    }
+}
+
+/// handle to the database file. reads are served from the copy loaded
+/// when opening it, changes are written back under the db lock and
+/// refresh that copy with anything other processes wrote meanwhile.
+pub struct DBHandle {
+    path: PathBuf,
+    db: DB,
+}
+
+impl DBHandle {
+    /// opens the db at its default location, see `db_path`
+    pub fn open() -> Result<DBHandle> {
+        DBHandle::open_at(&db_path()?)
+    }
+
+    /// opens the db at `path`, an empty db if the file doesn't exist yet
+    pub fn open_at(path: &Path) -> Result<DBHandle> {
+        DB::migrate_default_db(path)?;
+        Ok(DBHandle {
+            path: path.to_path_buf(),
+            db: DB::load_from(path)?,
+        })
+    }
+
+    /// location of the db file
+    pub fn path(&self) -> &Path {
+        self.path.as_path()
+    }
+
+    /// reads the db again, picking up changes of other processes
+    pub fn reload(&mut self) -> Result<()> {
+        self.db = DB::load_from(&self.path)?;
+        Ok(())
+    }
+
+    /// every project in the db, in the order they were added
+    pub fn projects(&self) -> &[Project] {
+        self.db.projects.as_slice()
+    }
+
+    /// the project tracked at exactly `path`
+    pub fn project(&self, path: &str) -> Result<&Project> {
+        self.db
+            .projects
+            .iter()
+            .find(|p| p.path == path)
+            .ok_or(anyhow!("no project found in path"))
+    }
+
+    /// returns the first project that matches with the path.
+    /// the path passed can be a subdirectory of a projects path.
+    /// for example:
+    /// if the project path is /home/user/project
+    /// and the path passed is /home/user/project/src/mod/a/b/c
+    /// the project will be returned.
+    pub fn project_for_dir(&self, dir: &Path) -> Result<&Project> {
+        let dir = dir.canonicalize()?;
+        let dir = dir.to_str().ok_or(anyhow!("bad path"))?;
+        self.db
+            .projects
+            .iter()
+            .find(|p| dir.starts_with(p.path.as_str()))
+            .ok_or(anyhow!("no project found in path"))
+    }
+
+    /// starts tracking the repository at `path`
+    pub fn add_project(&mut self, path: &str) -> Result<()> {
+        self.update(|db| {
+            db.add_project(Project::new(path.to_string()));
+            Ok(())
+        })
+    }
+
+    /// stops tracking the project at `path`
+    pub fn remove_project(&mut self, path: &str) -> Result<()> {
+        self.update(|db| {
+            db.remove_project(path);
+            Ok(())
+        })
+    }
+
+    /// saves `branch` as a branch, see `add_target` for tags and commits
+    pub fn add_branch(&mut self, path: &str, branch: String) -> Result<()> {
+        self.add_target(path, Branch::new(branch))
+    }
+
+    /// saves a branch, tag or commit to check out
+    pub fn add_target(&mut self, path: &str, branch: Branch) -> Result<()> {
+        self.update(|db| {
+            db.get_project_mut(path)
+                .ok_or(anyhow!("no project found"))?
+                .add_branch(branch);
+            Ok(())
+        })
+    }
+
+    /// forgets a saved branch, tag or commit
+    pub fn remove_branch(&mut self, path: &str, branch: String) -> Result<()> {
+        self.update(|db| {
+            db.get_project_mut(path)
+                .ok_or(anyhow!("no project found"))?
+                .remove_branch(branch);
+            Ok(())
+        })
+    }
+
+    /// only branches can be checked out in their own worktree
+    pub fn set_worktree(&mut self, path: &str, branch: &str, worktree: bool) -> Result<()> {
+        self.update(|db| {
+            let branch = db
+                .get_project_mut(path)
+                .ok_or(anyhow!("no project found"))?
+                .branches
+                .iter_mut()
+                .find(|b| b.name == branch)
+                .ok_or(anyhow!("branch {branch} is not saved in the project"))?;
+            if branch.kind != TargetKind::Branch {
+                bail!("{} is not a branch", branch.name);
+            }
+            branch.worktree = worktree;
+            Ok(())
+        })
+    }
+
+    /// files stashed when checking out in the project
+    pub fn set_stash_mode(&mut self, path: &str, mode: StashMode) -> Result<()> {
+        self.update(|db| {
+            db.get_project_mut(path)
+                .ok_or(anyhow!("no project found"))?
+                .stash_mode = mode;
+            Ok(())
+        })
+    }
+
+    /// moves a saved branch relative to where it is in the db, so
+    /// changes made by other processes since it was loaded are kept.
+    /// returns the new position of the branch.
+    pub fn move_branch(&mut self, path: &str, branch: &str, delta: isize) -> Result<usize> {
+        self.update(|db| {
+            db.get_project_mut(path)
+                .ok_or(anyhow!("no project found in path"))?
+                .move_branch(branch, delta)
+                .ok_or(anyhow!("branch {branch} is not saved in the project"))
+        })
+    }
+
+    fn update<T>(&mut self, f: impl FnOnce(&mut DB) -> Result<T>) -> Result<T> {
+        let mut updated = None;
+        let res = update_db_at(&self.path, |db| {
+            let res = f(db)?;
+            updated = Some(db.clone());
+            Ok(res)
+        })?;
+        if let Some(db) = updated {
+            self.db = db;
+        }
+        Ok(res)
+    }
+
+    /// returns every problem found in the db at `path` without
+    /// modifying it, once migrated from /etc like `open_at` does.
+    /// works on files `open_at` would refuse.
+    pub fn check_at(path: &Path) -> Result<Vec<DBIssue>> {
+        DB::migrate_default_db(path)?;
+        let (db, mut issues) = DB::parse_lenient(DB::read_db_file(path)?.as_str())?;
+        issues.extend(db.find_issues());
+        Ok(issues)
+    }
+
+    /// rewrites the db at `path` without the problems reported by
+    /// `check_at` and returns the problems that were fixed.
+    pub fn repair_at(path: &Path) -> Result<Vec<DBIssue>> {
+        DB::migrate_default_db(path)?;
+        let _lock = DBLock::acquire(path)?;
+        let (mut db, mut issues) = DB::parse_lenient(DB::read_db_file(path)?.as_str())?;
+        issues.extend(db.find_issues());
+        if !issues.is_empty() {
+            db.repair();
+            db.write_to(path)?;
+        }
+        Ok(issues)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_db() -> DB {
+        let mut db = DB::new();
+        let mut project = Project::new("/home/user/project".to_string());
+        project.add_branch(Branch::new("main".to_string()));
+        project.add_branch(Branch::new(";;;;weird".to_string()));
+        project.add_branch(Branch::new("feature/with spaces".to_string()));
+        project.add_branch(Branch {
+            kind: TargetKind::Tag,
+            ..Branch::new("v1.0".to_string())
+        });
+        project.add_branch(Branch {
+            worktree: true,
+            ..Branch::new("release".to_string())
+        });
+        db.add_project(project);
+        db.add_project(Project::new("/home/user/empty".to_string()));
+        db
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let db = sample_db();
+        let parsed = DB::parse(db.serialize().unwrap().as_str()).unwrap();
+        assert_eq!(parsed, db);
+    }
+
+    #[test]
+    fn legacy_round_trip() {
+        let legacy = ";;;;/home/user/project\nmain\ndev\n;;;;/home/user/empty\n";
+        let db = DB::parse(legacy).unwrap();
+        assert_eq!(db.version, DB_VERSION);
+        assert_eq!(db.projects.len(), 2);
+        assert_eq!(db.projects[0].path, "/home/user/project");
+        assert_eq!(
+            db.projects[0]
+                .branches
+                .iter()
+                .map(|b| b.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["main", "dev"]
+        );
+        assert!(db.projects[1].branches.is_empty());
+
+        let upgraded = DB::parse(db.serialize().unwrap().as_str()).unwrap();
+        assert_eq!(upgraded, db);
+    }
+
+    #[test]
+    fn empty_file_is_empty_db() {
+        assert_eq!(DB::parse("").unwrap(), DB::new());
+    }
+
+    #[test]
+    fn orphan_line_is_reported_with_line_number() {
+        let err = DB::parse("stray\n;;;;/home/user/project\nmain\n").unwrap_err();
+        match err.downcast_ref::<DBError>() {
+            Some(DBError::OrphanLine { line, content }) => {
+                assert_eq!(*line, 1);
+                assert_eq!(content, "stray");
+            }
+            _ => panic!("unexpected error: {err}"),
+        }
+    }
+
+    #[test]
+    fn repair_removes_issues() {
+        let dir = std::env::temp_dir();
+        let dir = dir.to_str().unwrap();
+        let contents =
+            format!("stray\n;;;;{dir}\nmain\nmain\n;;;;/does/not/exist\ndev\n;;;;{dir}\ndev\n");
+        let (mut db, mut issues) = DB::parse_lenient(contents.as_str()).unwrap();
+        db.projects[0].stash_mode = StashMode::All;
+        issues.extend(db.find_issues());
+        assert_eq!(
+            issues,
+            vec![
+                DBIssue::OrphanLine {
+                    line: 1,
+                    content: "stray".to_string()
+                },
+                DBIssue::DuplicateBranch {
+                    path: dir.to_string(),
+                    branch: "main".to_string()
+                },
+                DBIssue::MissingProjectDir {
+                    path: "/does/not/exist".to_string()
+                },
+                DBIssue::DuplicateProject {
+                    path: dir.to_string()
+                },
+            ]
+        );
+
+        db.repair();
+        assert!(db.find_issues().is_empty());
+        assert_eq!(db.projects.len(), 1);
+        assert_eq!(db.projects[0].stash_mode, StashMode::All);
+        assert_eq!(
+            db.projects[0]
+                .branches
+                .iter()
+                .map(|b| b.name.as_str())
+                .collect::<Vec<&str>>(),
+            vec!["main", "dev"]
+        );
+    }
+
+    #[test]
+    fn concurrent_updates_are_not_lost() {
+        let dir = std::env::temp_dir().join(format!("lgc-test-{}", std::process::id()));
+        let path = dir.join("db.json");
+        update_db_at(&path, |db| {
+            db.add_project(Project::new("/project".to_string()));
+            Ok(())
+        })
+        .unwrap();
+
+        let threads = 8;
+        let writes = 25;
+        let handles = (0..threads)
+            .map(|t| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    for w in 0..writes {
+                        update_db_at(&path, |db| {
+                            db.get_project_mut("/project")
+                                .unwrap()
+                                .add_branch(Branch::new(format!("branch-{t}-{w}")));
+                            Ok(())
+                        })
+                        .unwrap();
+                        // readers must always see a complete file
+                        DB::load_from(&path).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let db = DB::load_from(&path).unwrap();
+        assert_eq!(db.projects[0].branches.len(), threads * writes);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn reorder_keeps_changes_of_other_handles() {
+        let dir = TestDir::new();
+        let path = dir.0.join(DB_FILE_NAME);
+        let mut first = DBHandle::open_at(&path).unwrap();
+        first.add_project("/project").unwrap();
+        for name in ["main", "dev", "wip"] {
+            first.add_branch("/project", name.to_string()).unwrap();
+        }
+        first
+            .add_target(
+                "/project",
+                Branch {
+                    kind: TargetKind::Tag,
+                    ..Branch::new("v1".to_string())
+                },
+            )
+            .unwrap();
+
+        let mut second = DBHandle::open_at(&path).unwrap();
+        second.remove_branch("/project", "dev".to_string()).unwrap();
+        assert_eq!(first.move_branch("/project", "v1", -1).unwrap(), 1);
+        // wraps around the top of the list
+        assert_eq!(first.move_branch("/project", "main", -1).unwrap(), 2);
+
+        second.reload().unwrap();
+        let branches = &second.project("/project").unwrap().branches;
+        let names = branches.iter().map(|b| b.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, vec!["v1", "wip", "main"]);
+        assert_eq!(branches[0].kind, TargetKind::Tag);
+    }
+
+    #[test]
+    fn db_path_precedence() {
+        let resolve = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect::<std::collections::HashMap<String, String>>();
+            resolve_db_path(|key| vars.get(key).cloned()).ok()
+        };
+        let all = [
+            (DB_PATH_ENV, "/custom/db"),
+            ("XDG_DATA_HOME", "/xdg"),
+            ("HOME", "/home/user"),
+        ];
+        assert_eq!(resolve(&all), Some(PathBuf::from("/custom/db")));
+        assert_eq!(
+            resolve(&all[1..]),
+            Some(PathBuf::from(format!("/xdg/{DB_DIR_NAME}/{DB_FILE_NAME}")))
+        );
+        assert_eq!(
+            resolve(&all[2..]),
+            Some(PathBuf::from(format!(
+                "/home/user/.local/share/{DB_DIR_NAME}/{DB_FILE_NAME}"
+            )))
+        );
+        assert_eq!(resolve(&[]), None);
+    }
+
+    #[test]
+    fn legacy_db_is_migrated_once() {
+        let dir = TestDir::new();
+        let legacy = dir.0.join("legacy.db.txt");
+        std::fs::write(&legacy, ";;;;/home/user/project\nmain\n").unwrap();
+        let path = dir.0.join(DB_DIR_NAME).join(DB_FILE_NAME);
+        // taking the lock first creates the directory
+        drop(DBLock::acquire(&path).unwrap());
+
+        DB::migrate_legacy_db(&legacy, &path).unwrap();
+        assert_eq!(DB::load_from(&path).unwrap().projects.len(), 1);
+
+        // the new location is in use, removing its db starts over
+        std::fs::remove_file(&path).unwrap();
+        DB::migrate_legacy_db(&legacy, &path).unwrap();
+        assert!(!path.exists());
+        assert!(legacy.exists());
+    }
+
+    #[test]
+    fn newer_version_is_rejected() {
+        let contents = format!("{{\"version\": {}, \"projects\": []}}", DB_VERSION + 1);
+        assert!(DB::parse(contents.as_str()).is_err());
+    }
+
+    static TEST_DIRS: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+    // empty directory removed when dropped
+    struct TestDir(PathBuf);
+
+    impl TestDir {
+        fn new() -> TestDir {
+            let n = TEST_DIRS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            let dir = std::env::temp_dir().join(format!("lgc-test-{}-{n}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir).unwrap();
+            TestDir(dir)
+        }
+    }
+
+    impl Drop for TestDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn fake_git(dir: &TestDir) -> (Rc<backend::FakeBackend>, Git) {
+        let fake = Rc::new(backend::FakeBackend::new(dir.0.clone(), &["main", "dev"]));
+        let git = Git::with_backend(dir.0.to_str().unwrap().to_string(), fake.clone());
+        (fake, git)
+    }
+
+    #[test]
+    fn fake_checkout_stashes_and_pops() {
+        let dir = TestDir::new();
+        let (fake, git) = fake_git(&dir);
+        fake.repo().changes = vec![" M f".to_string()];
+        let opts = CheckoutOptions::default();
+
+        git.checkout("dev", &opts).unwrap();
+        assert_eq!(fake.repo().head, "dev");
+        assert!(fake.repo().changes.is_empty());
+        let sha = fake.repo().stashes[0].sha.clone();
+        assert_eq!(git.branch_stash("main").unwrap(), Some(sha));
+
+        git.checkout("main", &opts).unwrap();
+        assert_eq!(fake.repo().head, "main");
+        assert_eq!(fake.repo().changes, vec![" M f"]);
+        assert!(fake.repo().stashes.is_empty());
+        assert!(fake.repo().refs.is_empty());
+        assert!(git.interrupted_checkout().unwrap().is_none());
+    }
+
+    #[test]
+    fn fake_checkout_refuses_operation_in_progress() {
+        let dir = TestDir::new();
+        let (fake, git) = fake_git(&dir);
+        fake.repo().changes = vec![" M f".to_string()];
+        std::fs::create_dir(dir.0.join("rebase-merge")).unwrap();
+
+        let err = git
+            .checkout("dev", &CheckoutOptions::default())
+            .unwrap_err();
+        let err = err.downcast::<OperationInProgress>().unwrap();
+        assert_eq!(err.operation, GitOperation::Rebase);
+        assert_eq!(fake.repo().head, "main");
+        assert!(fake.repo().stashes.is_empty());
+
+        let opts = CheckoutOptions {
+            force: true,
+            ..Default::default()
+        };
+        git.checkout("dev", &opts).unwrap();
+        assert_eq!(fake.repo().head, "dev");
+    }
+
+    #[test]
+    fn fake_checkout_honors_stash_mode() {
+        let dir = TestDir::new();
+        let (fake, git) = fake_git(&dir);
+        fake.repo().changes = vec!["?? new".to_string()];
+
+        git.checkout("dev", &CheckoutOptions::default()).unwrap();
+        assert!(fake.repo().stashes.is_empty());
+        assert_eq!(fake.repo().changes, vec!["?? new"]);
+
+        let opts = CheckoutOptions {
+            stash_mode: StashMode::IncludeUntracked,
+            ..Default::default()
+        };
+        git.checkout("main", &opts).unwrap();
+        assert_eq!(fake.repo().stashes.len(), 1);
+        assert!(fake.repo().changes.is_empty());
+    }
+
+    #[test]
+    fn fake_checkout_of_missing_branch_is_rolled_back() {
+        let dir = TestDir::new();
+        let (fake, git) = fake_git(&dir);
+        fake.repo().changes = vec![" M f".to_string()];
+
+        assert!(git.checkout("nope", &CheckoutOptions::default()).is_err());
+        assert_eq!(fake.repo().head, "main");
+        assert_eq!(fake.repo().changes, vec![" M f"]);
+        assert!(fake.repo().stashes.is_empty());
+        assert!(fake.repo().refs.is_empty());
+        assert!(git.interrupted_checkout().unwrap().is_none());
+    }
+
+    // real repository with a `main` and a `dev` branch, both with a
+    // committed file `f`.
+    struct TestRepo {
+        dir: TestDir,
+        git: Git,
+    }
+
+    impl TestRepo {
+        fn new() -> TestRepo {
+            let dir = TestDir::new();
+            let git = Git::new(dir.0.to_str().unwrap().to_string());
+            let repo = TestRepo { dir, git };
+            repo.run(&["init", "-q", "-b", "main"]);
+            repo.run(&["config", "user.name", "lgc"]);
+            repo.run(&["config", "user.email", "lgc@example.com"]);
+            repo.run(&["config", "commit.gpgsign", "false"]);
+            repo.write("f", "a\n");
+            repo.run(&["add", "f"]);
+            repo.run(&["commit", "-q", "-m", "initial"]);
+            repo.run(&["branch", "dev"]);
+            repo
+        }
+
+        fn run(&self, args: &[&str]) -> String {
+            let output = self.git.run_git_command(args.to_vec()).unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        }
+
+        fn write(&self, file: &str, contents: &str) {
+            std::fs::write(self.dir.0.join(file), contents).unwrap();
+        }
+
+        fn read(&self, file: &str) -> String {
+            std::fs::read_to_string(self.dir.0.join(file)).unwrap()
+        }
+
+        fn status(&self) -> String {
+            self.run(&["status", "--porcelain"])
+        }
+
+        fn stash_subjects(&self) -> Vec<String> {
+            self.run(&["stash", "list", "--format=%gs"])
+                .lines()
+                .map(|l| l.to_string())
+                .collect()
+        }
+
+        fn checkout(&self, branch: &str) -> Result<()> {
+            self.git.checkout(branch, &CheckoutOptions::default())
+        }
+
+        // runs the checkout to `branch` by hand and leaves it as if
+        // lgc was killed right before running `step`. at the stash
+        // step the stash is pushed but its sha not recorded yet.
+        fn interrupt_checkout(&self, branch: &str, step: CheckoutStep) -> CheckoutJournal {
+            let git = &self.git;
+            let from = git.current_stash_key().unwrap();
+            let mut journal = CheckoutJournal {
+                from_branch: from.clone(),
+                to_branch: branch.to_string(),
+                step,
+                stash_before: git.last_stash_sha().unwrap(),
+                stashed: None,
+                popping: None,
+            };
+            let message = format!("{STASH_MESSAGE_PREFIX}{from}");
+            let sha = git.stash(message.as_str(), StashMode::Tracked).unwrap();
+            if step != CheckoutStep::Stash {
+                journal.stashed = sha.clone();
+                git.save_branch_stash(from.as_str(), sha.unwrap().as_str())
+                    .unwrap();
+            }
+            if step == CheckoutStep::Pop {
+                git.backend.checkout(branch).unwrap();
+                journal.popping = git.branch_stash(branch).unwrap();
+            }
+            git.write_journal(&journal).unwrap();
+            journal
+        }
+    }
+
+    #[test]
+    fn checkout_with_clean_tree_does_not_stash() {
+        let repo = TestRepo::new();
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "dev");
+        assert_eq!(repo.status(), "");
+        assert!(repo.stash_subjects().is_empty());
+        assert!(repo.git.branch_stash("main").unwrap().is_none());
+    }
+
+    #[test]
+    fn checkout_restores_changes_and_index() {
+        let repo = TestRepo::new();
+        repo.write("f", "changed\n");
+        repo.write("g", "staged\n");
+        repo.run(&["add", "g"]);
+
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.status(), "");
+        assert_eq!(repo.read("f"), "a\n");
+        assert_eq!(
+            repo.stash_subjects(),
+            vec!["On main: lazy-git-checkout:main"]
+        );
+
+        repo.checkout("main").unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "main");
+        assert_eq!(repo.status(), " M f\nA  g\n");
+        assert_eq!(repo.read("f"), "changed\n");
+        assert!(repo.stash_subjects().is_empty());
+    }
+
+    #[test]
+    fn stash_is_popped_without_index_when_it_does_not_apply() {
+        let repo = TestRepo::new();
+        let lines = |changed: &[(usize, &str)]| {
+            let mut lines = (1..=9).map(|n| n.to_string()).collect::<Vec<String>>();
+            for (n, line) in changed {
+                lines[n - 1] = line.to_string();
+            }
+            lines.join("\n") + "\n"
+        };
+        repo.write("f", lines(&[]).as_str());
+        repo.run(&["commit", "-q", "-am", "lines"]);
+        repo.write("f", lines(&[(5, "five")]).as_str());
+        repo.run(&["add", "f"]);
+        let sha = repo.git.stash("staged", StashMode::Tracked).unwrap();
+        // the staged change no longer applies on its own, but merges
+        repo.write("f", lines(&[(3, "three")]).as_str());
+        repo.run(&["commit", "-q", "-am", "change line 3"]);
+
+        let mode = repo.git.pop_stash(sha.unwrap().as_str()).unwrap();
+        assert_eq!(mode, PopMode::WithoutIndex);
+        assert_eq!(repo.status(), " M f\n");
+        assert_eq!(repo.read("f"), lines(&[(3, "three"), (5, "five")]));
+        assert!(repo.stash_subjects().is_empty());
+    }
+
+    #[test]
+    fn checkout_of_missing_branch_is_rolled_back() {
+        let repo = TestRepo::new();
+        repo.write("f", "changed\n");
+
+        assert!(repo.checkout("nope").is_err());
+        assert_eq!(repo.git.get_current_branch().unwrap(), "main");
+        assert_eq!(repo.status(), " M f\n");
+        assert!(repo.stash_subjects().is_empty());
+        assert!(repo.git.interrupted_checkout().unwrap().is_none());
+    }
+
+    #[test]
+    fn conflicting_pop_keeps_the_stash() {
+        let repo = repo_with_conflicting_stash();
+
+        let err = repo.checkout("dev").unwrap_err();
+        let conflict = err.downcast_ref::<StashConflict>().unwrap();
+        assert_eq!(conflict.files, vec!["f"]);
+        assert_eq!(repo.stash_subjects(), vec!["On dev: lazy-git-checkout:dev"]);
+
+        repo.git.abort_conflict().unwrap();
+        assert_eq!(repo.status(), "");
+        assert_eq!(repo.read("f"), "committed\n");
+        assert_eq!(repo.stash_subjects().len(), 1);
+    }
+
+    // on a clean main, with a stash for dev that conflicts with the
+    // commit made on dev after it
+    fn repo_with_conflicting_stash() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.checkout("dev").unwrap();
+        repo.write("f", "pending\n");
+        repo.checkout("main").unwrap();
+        repo.run(&["checkout", "-q", "dev"]);
+        repo.write("f", "committed\n");
+        repo.run(&["commit", "-q", "-am", "change f"]);
+        repo.run(&["checkout", "-q", "main"]);
+        repo
+    }
+
+    // on a clean main, with a stash for main on top of one for dev
+    fn repo_with_stashes() -> TestRepo {
+        let repo = TestRepo::new();
+        repo.checkout("dev").unwrap();
+        repo.write("f", "dev\n");
+        repo.checkout("main").unwrap();
+        repo.write("f", "main\n");
+        let message = format!("{STASH_MESSAGE_PREFIX}main");
+        let sha = repo
+            .git
+            .stash(message.as_str(), StashMode::Tracked)
+            .unwrap();
+        repo.git
+            .save_branch_stash("main", sha.unwrap().as_str())
+            .unwrap();
+        repo
+    }
+
+    fn lgc_stash(repo: &TestRepo, branch: &str) -> Option<LgcStash> {
+        repo.git
+            .lgc_stashes()
+            .unwrap()
+            .into_iter()
+            .find(|s| s.branch == branch)
+    }
+
+    #[test]
+    fn stashes_are_applied_popped_and_dropped() {
+        let repo = repo_with_stashes();
+        let main = lgc_stash(&repo, "main").unwrap();
+        repo.git.apply_stash(&main).unwrap();
+        assert_eq!(repo.read("f"), "main\n");
+        assert_eq!(repo.git.lgc_stashes().unwrap().len(), 2);
+        assert!(lgc_stash(&repo, "main").unwrap().tracked);
+
+        repo.run(&["checkout", "-q", "--", "f"]);
+        repo.git.pop_lgc_stash(&main).unwrap();
+        assert_eq!(repo.read("f"), "main\n");
+        assert!(lgc_stash(&repo, "main").is_none());
+        assert!(repo.git.branch_stash("main").unwrap().is_none());
+
+        let dev = lgc_stash(&repo, "dev").unwrap();
+        repo.git.drop_stash(&dev).unwrap();
+        assert!(repo.git.lgc_stashes().unwrap().is_empty());
+        assert!(repo.git.branch_stash("dev").unwrap().is_none());
+    }
+
+    #[test]
+    fn renamed_stash_keeps_its_place() {
+        let repo = repo_with_stashes();
+        let dev = lgc_stash(&repo, "dev").unwrap();
+        repo.git.rename_stash(&dev, " wip ").unwrap();
+
+        let stashes = repo.git.lgc_stashes().unwrap();
+        assert_eq!(stashes.len(), 2);
+        let renamed = lgc_stash(&repo, "dev").unwrap();
+        assert_eq!(renamed.sha, dev.sha);
+        assert_eq!(renamed.label, "wip");
+        assert!(renamed.tracked);
+        assert_eq!(repo.git.branch_stash("dev").unwrap(), Some(dev.sha));
+
+        // the newest stash can't be stored on top of itself
+        let top = repo.git.lgc_stashes().unwrap()[0].clone();
+        repo.git.rename_stash(&top, "top").unwrap();
+        let renamed = lgc_stash(&repo, top.branch.as_str()).unwrap();
+        assert_eq!(renamed.sha, top.sha);
+        assert_eq!(renamed.label, "top");
+        assert_eq!(repo.git.lgc_stashes().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn stash_is_moved_only_to_a_branch_without_one() {
+        let repo = repo_with_stashes();
+        let dev = lgc_stash(&repo, "dev").unwrap();
+        assert!(repo.git.move_stash(&dev, "main", &[]).is_err());
+        assert!(repo.git.move_stash(&dev, "mian", &[]).is_err());
+        assert_eq!(repo.git.lgc_stashes().unwrap().len(), 2);
+        assert_eq!(repo.git.branch_stash("dev").unwrap(), Some(dev.sha.clone()));
+
+        repo.git
+            .drop_stash(&lgc_stash(&repo, "main").unwrap())
+            .unwrap();
+        repo.git.move_stash(&dev, "main", &[]).unwrap();
+        assert!(repo.git.branch_stash("dev").unwrap().is_none());
+        assert_eq!(repo.git.branch_stash("main").unwrap(), Some(dev.sha));
+
+        repo.checkout("dev").unwrap();
+        repo.checkout("main").unwrap();
+        assert_eq!(repo.read("f"), "dev\n");
+        assert!(repo.stash_subjects().is_empty());
+    }
+
+    #[test]
+    fn stash_is_moved_to_a_saved_tag_by_its_commit() {
+        let repo = repo_with_stashes();
+        repo.run(&["tag", "v1", "dev"]);
+        let dev = lgc_stash(&repo, "dev").unwrap();
+        assert!(repo.git.move_stash(&dev, "v1", &[]).is_err());
+
+        let tag = repo.git.checkout_target("v1").unwrap();
+        repo.git.move_stash(&dev, "v1", &[tag]).unwrap();
+        let sha = repo.run(&["rev-parse", "dev"]).trim().to_string();
+        assert_eq!(repo.git.branch_stash(sha.as_str()).unwrap(), Some(dev.sha));
+    }
+
+    #[test]
+    fn aborted_conflict_is_offered_again() {
+        let repo = repo_with_conflicting_stash();
+
+        let err = repo.checkout("dev").unwrap_err();
+        let stash = err.downcast_ref::<StashConflict>().unwrap().stash.clone();
+        repo.git.abort_conflict().unwrap();
+
+        repo.checkout("main").unwrap();
+        let err = repo.checkout("dev").unwrap_err();
+        let conflict = err.downcast_ref::<StashConflict>().unwrap();
+        assert_eq!(conflict.stash, stash);
+        assert_eq!(conflict.files, vec!["f"]);
+    }
+
+    #[test]
+    fn clean_checkout_keeps_the_pending_stash() {
+        let repo = TestRepo::new();
+        repo.checkout("dev").unwrap();
+        repo.write("f", "pending\n");
+        repo.checkout("main").unwrap();
+        // back on dev without lgc, the stash is still pending
+        repo.run(&["checkout", "-q", "dev"]);
+
+        repo.checkout("main").unwrap();
+        assert_eq!(repo.stash_subjects(), vec!["On dev: lazy-git-checkout:dev"]);
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.read("f"), "pending\n");
+        assert!(repo.stash_subjects().is_empty());
+    }
+
+    #[test]
+    fn legacy_stash_is_adopted_once() {
+        let repo = TestRepo::new();
+        repo.run(&["checkout", "-q", "dev"]);
+        for contents in ["stale\n", "legacy\n"] {
+            repo.write("f", contents);
+            repo.run(&["stash", "push", "-q", "-m", "lazy-git-checkout:dev"]);
+        }
+        repo.run(&["checkout", "-q", "main"]);
+
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.read("f"), "legacy\n");
+        repo.run(&["checkout", "-q", "--", "f"]);
+        repo.checkout("main").unwrap();
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.read("f"), "a\n");
+        assert_eq!(repo.stash_subjects(), vec!["On dev: lazy-git-checkout:dev"]);
+    }
+
+    #[test]
+    fn stash_diff_only_reads_the_repo() {
+        let repo = TestRepo::new();
+        repo.write("f", "legacy\n");
+        repo.run(&["stash", "push", "-q", "-m", "lazy-git-checkout:dev"]);
+        assert_eq!(repo.git.branch_stash_diff("dev").unwrap(), None);
+        assert_eq!(repo.git.read_ref(LEGACY_STASHES_REF).unwrap(), None);
+
+        repo.checkout("dev").unwrap();
+        repo.write("f", "pending\n");
+        repo.checkout("main").unwrap();
+        let diff = repo.git.branch_stash_diff("dev").unwrap().unwrap();
+        assert!(diff.contains("+pending"));
+
+        // dropped outside of lgc, the ref is left for checkout to clear
+        repo.run(&["stash", "drop", "-q"]);
+        assert_eq!(repo.git.branch_stash_diff("dev").unwrap(), None);
+        assert!(repo
+            .git
+            .read_ref(branch_stash_ref("dev").as_str())
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn labeled_legacy_stash_is_adopted() {
+        let repo = TestRepo::new();
+        repo.run(&["checkout", "-q", "dev"]);
+        repo.write("f", "legacy\n");
+        repo.run(&["stash", "push", "-q", "-m", "lazy-git-checkout:dev wip"]);
+        repo.write("f", "bad\n");
+        repo.run(&["stash", "push", "-q", "-m", "lazy-git-checkout:dev..x"]);
+        repo.run(&["checkout", "-q", "main"]);
+
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.read("f"), "legacy\n");
+        assert_eq!(
+            repo.stash_subjects(),
+            vec!["On dev: lazy-git-checkout:dev..x"]
+        );
+    }
+
+    #[test]
+    fn failed_stash_is_not_left_interrupted() {
+        let repo = repo_with_conflicting_stash();
+        assert!(repo.checkout("dev").is_err());
+
+        // the conflicted file can't be stashed
+        assert!(repo.checkout("main").is_err());
+        assert!(repo.git.interrupted_checkout().unwrap().is_none());
+        assert_eq!(repo.git.get_current_branch().unwrap(), "dev");
+        assert!(repo.status().starts_with("UU f"));
+    }
+
+    // dev has a pending stash and main changes when the checkout to dev
+    // is interrupted at each step
+    fn interrupted_repo(step: CheckoutStep) -> (TestRepo, CheckoutJournal) {
+        let repo = TestRepo::new();
+        repo.checkout("dev").unwrap();
+        repo.write("f", "dev\n");
+        repo.checkout("main").unwrap();
+        repo.write("f", "main\n");
+        let journal = repo.interrupt_checkout("dev", step);
+        assert!(repo.checkout("main").is_err());
+        (repo, journal)
+    }
+
+    #[test]
+    fn interrupted_checkout_is_finished() {
+        for step in [
+            CheckoutStep::Stash,
+            CheckoutStep::Checkout,
+            CheckoutStep::Pop,
+        ] {
+            let (repo, journal) = interrupted_repo(step);
+            repo.git
+                .finish_checkout(&journal, &CheckoutOptions::default())
+                .unwrap();
+            assert!(repo.git.interrupted_checkout().unwrap().is_none());
+            assert_eq!(repo.git.get_current_branch().unwrap(), "dev");
+            assert_eq!(repo.read("f"), "dev\n", "{step:?}");
+
+            repo.checkout("main").unwrap();
+            assert_eq!(repo.read("f"), "main\n", "{step:?}");
+            assert_eq!(repo.stash_subjects(), vec!["On dev: lazy-git-checkout:dev"]);
+        }
+    }
+
+    #[test]
+    fn interrupted_checkout_is_undone() {
+        for (step, popped) in [
+            (CheckoutStep::Stash, false),
+            (CheckoutStep::Checkout, false),
+            (CheckoutStep::Pop, false),
+            (CheckoutStep::Pop, true),
+        ] {
+            let (repo, journal) = interrupted_repo(step);
+            if popped {
+                // killed after the pop finished
+                let sha = journal.popping.as_deref().unwrap();
+                repo.git.pop_branch_stash("dev", sha).unwrap();
+            }
+            repo.git.undo_checkout(&journal).unwrap();
+            assert!(repo.git.interrupted_checkout().unwrap().is_none());
+            assert_eq!(repo.git.get_current_branch().unwrap(), "main");
+            assert_eq!(repo.read("f"), "main\n", "{step:?}");
+
+            repo.checkout("dev").unwrap();
+            assert_eq!(repo.read("f"), "dev\n", "{step:?}");
+        }
+    }
+
+    #[test]
+    fn branch_info_counts_commits_ahead_and_behind_upstream() {
+        let repo = TestRepo::new();
+        repo.run(&["remote", "add", "origin", repo.dir.0.to_str().unwrap()]);
+        repo.write("f", "upstream\n");
+        repo.run(&["commit", "-q", "-am", "upstream change"]);
+        repo.run(&["update-ref", "refs/remotes/origin/dev", "HEAD"]);
+        repo.run(&["checkout", "-q", "dev"]);
+        for n in 1..=2 {
+            repo.write("g", n.to_string().as_str());
+            repo.run(&["add", "g"]);
+            repo.run(&["commit", "-q", "-m", format!("local change {n}").as_str()]);
+        }
+
+        assert_eq!(repo.git.branch_info("dev").unwrap().upstream, None);
+        repo.run(&["branch", "-q", "--set-upstream-to=origin/dev", "dev"]);
+        let info = repo.git.branch_info("dev").unwrap();
+        assert_eq!(info.subject, "local change 2");
+        assert_eq!(info.author, "lgc");
+        assert_eq!(info.upstream.as_deref(), Some("origin/dev"));
+        assert_eq!((info.ahead, info.behind), (2, 1));
+    }
+
+    #[test]
+    fn checkout_of_remote_branch_creates_tracking_branch() {
+        let repo = TestRepo::new();
+        repo.run(&["remote", "add", "origin", repo.dir.0.to_str().unwrap()]);
+        repo.run(&["update-ref", "refs/remotes/origin/feature", "HEAD"]);
+        repo.write("f", "changed\n");
+
+        repo.checkout("remotes/origin/feature").unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "feature");
+        assert_eq!(
+            repo.run(&["rev-parse", "--abbrev-ref", "feature@{upstream}"]),
+            "origin/feature\n"
+        );
+        assert_eq!(
+            repo.git
+                .remote_branch("origin/feature")
+                .unwrap()
+                .unwrap()
+                .name,
+            "feature"
+        );
+        assert!(repo.git.remote_branch("dev").unwrap().is_none());
+
+        repo.checkout("main").unwrap();
+        assert_eq!(repo.read("f"), "changed\n");
+    }
+
+    #[test]
+    fn branches_are_typed_by_kind() {
+        let repo = TestRepo::new();
+        repo.run(&["remote", "add", "origin", repo.dir.0.to_str().unwrap()]);
+        repo.run(&["update-ref", "refs/remotes/origin/main", "HEAD"]);
+        repo.run(&[
+            "symbolic-ref",
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/main",
+        ]);
+        repo.run(&["branch", "-q", "--set-upstream-to", "origin/main", "main"]);
+
+        let sha = repo.run(&["rev-parse", "HEAD"]);
+        let branches = repo.git.all_project_branches().unwrap();
+        let kinds: Vec<(&str, BranchKind, Option<&str>)> = branches
+            .iter()
+            .map(|b| (b.name.as_str(), b.kind, b.upstream.as_deref()))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                ("dev", BranchKind::Local, None),
+                ("main", BranchKind::Local, Some("origin/main")),
+                ("origin/HEAD", BranchKind::Head, None),
+                ("origin/main", BranchKind::Remote, None),
+            ]
+        );
+        assert!(branches.iter().all(|b| b.sha == sha.trim() && b.date > 0));
+    }
+
+    #[test]
+    fn branch_worktree_is_added_once_and_pruned() {
+        let repo = TestRepo::new();
+        let path = repo.git.branch_worktree("dev").unwrap();
+        let name = repo.dir.0.file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            path,
+            repo.dir
+                .0
+                .with_file_name(format!("{name}.worktrees"))
+                .join("dev")
+        );
+        assert_eq!(std::fs::read_to_string(path.join("f")).unwrap(), "a\n");
+        assert_eq!(repo.git.branch_worktree("dev").unwrap(), path);
+        assert_eq!(
+            repo.git.branch_worktree("main").unwrap(),
+            repo.git.toplevel().unwrap()
+        );
+
+        let worktrees = repo.git.worktrees().unwrap();
+        assert_eq!(
+            worktrees
+                .iter()
+                .map(|w| (w.branch.as_deref(), w.prunable))
+                .collect::<Vec<(Option<&str>, bool)>>(),
+            vec![(Some("main"), false), (Some("dev"), false)]
+        );
+
+        std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert!(repo.git.worktrees().unwrap()[1].prunable);
+        repo.git.prune_worktrees().unwrap();
+        assert_eq!(repo.git.worktrees().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn branch_wins_over_tag_with_its_name() {
+        let repo = TestRepo::new();
+        repo.run(&["tag", "dev"]);
+        repo.run(&["checkout", "-q", "dev"]);
+        repo.write("f", "dev\n");
+        repo.run(&["commit", "-q", "-am", "change f"]);
+        repo.run(&["checkout", "-q", "main"]);
+
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "dev");
+        assert_eq!(repo.read("f"), "dev\n");
+        assert_eq!(repo.status(), "");
+    }
+
+    #[test]
+    fn checkout_from_detached_head() {
+        let repo = TestRepo::new();
+        repo.run(&["checkout", "-q", "--detach"]);
+        repo.write("f", "changed\n");
+
+        let sha = repo.run(&["rev-parse", "HEAD"]).trim().to_string();
+        repo.checkout("dev").unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "dev");
+        assert_eq!(repo.status(), "");
+        assert_eq!(
+            repo.stash_subjects(),
+            vec![format!("On (no branch): lazy-git-checkout:{sha}")]
+        );
+        assert!(repo.git.branch_stash(sha.as_str()).unwrap().is_some());
+
+        repo.checkout(sha.as_str()).unwrap();
+        assert_eq!(repo.git.get_current_branch().unwrap(), "HEAD");
+        assert_eq!(repo.read("f"), "changed\n");
+    }
+
+    #[test]
+    fn tags_and_commits_share_the_stash_of_their_commit() {
+        let repo = TestRepo::new();
+        repo.run(&["tag", "v1.0"]);
+        let sha = repo.run(&["rev-parse", "HEAD"]).trim().to_string();
+        repo.run(&["commit", "-q", "--allow-empty", "-m", "second"]);
+
+        repo.checkout("v1.0").unwrap();
+        repo.write("f", "on the tag\n");
+        repo.checkout("main").unwrap();
+        assert_eq!(repo.read("f"), "a\n");
+        repo.checkout(&sha[..7]).unwrap();
+        assert_eq!(repo.read("f"), "on the tag\n");
+
+        let tag = repo.git.checkout_target("v1.0").unwrap();
+        assert_eq!(tag.kind, TargetKind::Tag);
+        let commit = repo.git.checkout_target(&sha[..7]).unwrap();
+        assert_eq!(
+            (commit.name.as_str(), commit.kind),
+            (sha.as_str(), TargetKind::Commit)
+        );
+        assert_eq!(
+            repo.git.checkout_target("dev").unwrap(),
+            Branch::new("dev".to_string())
+        );
+
+        let targets = [tag, commit, Branch::new("main".to_string())];
+        assert_eq!(
+            repo.git.checked_out(&targets).unwrap(),
+            vec!["v1.0".to_string(), sha.clone()]
+        );
     }
 }
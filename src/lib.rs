@@ -0,0 +1,32 @@
+//! Change branches while stashing and unstashing changes.
+//!
+//! Projects and their saved branches are tracked in a [`DBHandle`],
+//! and [`Git::checkout`] moves between branches stashing the working
+//! tree of the branch being left and popping the one saved for the
+//! branch being checked out.
+//!
+//! ```no_run
+//! use lazy_git_checkout::{CheckoutOptions, DBHandle, Git};
+//!
+//! let db = DBHandle::open()?;
+//! let project = db.project_for_dir(&std::env::current_dir()?)?;
+//! let git = Git::new(project.path.clone());
+//! git.checkout("main", &CheckoutOptions::default())?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+/// the git operations `Git` runs its checkouts through
+pub mod backend;
+mod core;
+/// shell wrappers that cd into the directory lgc switches to
+pub mod shell;
+// terminal entry points of the binary, not part of the api
+#[doc(hidden)]
+pub mod ui;
+mod widgets;
+
+pub use crate::core::{
+    db_path, Branch, BranchInfo, BranchKind, BranchRef, CheckoutJournal, CheckoutOptions,
+    CheckoutStep, DBError, DBHandle, DBIssue, Git, GitOperation, LgcStash, OperationInProgress,
+    PopMode, Project, RemoteBranch, StashConflict, StashMode, TargetKind, Worktree,
+};
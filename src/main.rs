@@ -1,45 +1,221 @@
-use core::Project;
-use std::path::Path;
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
+use lazy_git_checkout::{
+    db_path, shell, ui, CheckoutJournal, CheckoutOptions, DBHandle, Git, Project, StashMode,
+    TargetKind,
+};
 
 mod cli;
-mod core;
-mod ui;
-mod widgets;
 
 fn main() -> Result<()> {
     let args = cli::CLIArgs::parse();
 
+    let db_path = match args.db {
+        Some(path) => path,
+        None => db_path()?,
+    };
+
+    if let Some(command) = args.command {
+        return run_command(command, db_path, args.stash_mode, args.force);
+    }
+
+    let mut db = DBHandle::open_at(&db_path)?;
+
     if let Some(branch) = args.checkout {
-        let proj = cur_project()?;
-        let git = core::Git::new(proj.path);
-        git.checkout(branch.as_str())?;
+        let proj = cur_project(&db)?;
+        let opts = CheckoutOptions::for_project(&proj, args.stash_mode, args.force);
+        let git = cur_git(&proj)?;
+        if proj.branches.iter().any(|b| b.name == branch && b.worktree) {
+            shell::change_dir(git.branch_worktree(branch.as_str())?.as_path())?;
+        } else {
+            ui::handle_stash_conflict(&git, git.checkout(branch.as_str(), &opts))?;
+        }
+    } else if let Some(mode) = args.set_stash_mode {
+        let proj = cur_project(&db)?;
+        db.set_stash_mode(proj.path.as_str(), mode)?;
     } else if let Some(branch) = args.add {
-        let proj = cur_project()?;
-        core::add_branch(proj.path.as_str(), branch)?;
+        let proj = cur_project(&db)?;
+        // remote branches are saved as the local branch tracking them
+        let git = cur_git(&proj)?;
+        let branch = match git.remote_branch(branch.as_str())? {
+            Some(remote) => git.track_remote_branch(&remote)?,
+            None => branch,
+        };
+        db.add_target(proj.path.as_str(), git.checkout_target(branch.as_str())?)?;
     } else if let Some(branch) = args.remove {
-        let proj = cur_project()?;
-        core::remove_branch(proj.path.as_str(), branch)?;
+        let proj = cur_project(&db)?;
+        db.remove_branch(proj.path.as_str(), branch)?;
     } else if let Some(project) = args.add_project {
         let path = Path::new(project.as_str());
-        core::add_project(path.canonicalize()?.to_str().ok_or(anyhow!("bad path"))?)?;
+        db.add_project(path.canonicalize()?.to_str().ok_or(anyhow!("bad path"))?)?;
     } else if let Some(project) = args.remove_project {
-        core::remove_project(project.as_str())?;
+        db.remove_project(project.as_str())?;
     } else if args.list {
-        core::list_projects()?;
+        for project in db.projects() {
+            println!("{}", project.path);
+            for branch in &project.branches {
+                match branch.kind {
+                    TargetKind::Branch if branch.worktree => {
+                        println!("  {} (worktree)", branch.name)
+                    }
+                    TargetKind::Branch => println!("  {}", branch.name),
+                    TargetKind::Tag => println!("  {} (tag)", branch.name),
+                    TargetKind::Commit => println!("  {} (commit)", branch.name),
+                }
+            }
+        }
     } else {
-        let proj = cur_project()?;
-        let git: core::Git = core::Git::new(proj.path.clone());
-        ui::start_ui(proj, git)?;
+        let proj = match cur_project(&db) {
+            Ok(proj) => proj,
+            // outside of any project, pick one from the db
+            Err(_) if !db.projects().is_empty() => {
+                return ui::start_project_picker(db, args.stash_mode, args.force);
+            }
+            Err(err) => return Err(err),
+        };
+        let git = cur_git(&proj)?;
+        if let Some(journal) = git.interrupted_checkout()? {
+            bail!("{journal}, run `lazy-git-checkout recover` to finish or undo it");
+        }
+        ui::start_ui(db, proj, git, args.stash_mode, args.force)?;
     }
 
     Ok(())
 }
 
-fn cur_project() -> Result<Project> {
+fn run_command(
+    command: cli::Command,
+    db_path: PathBuf,
+    stash_mode: Option<StashMode>,
+    force: bool,
+) -> Result<()> {
+    match command {
+        cli::Command::Db { command } => match command {
+            cli::DBCommand::Check => {
+                let issues = DBHandle::check_at(&db_path)?;
+                for issue in &issues {
+                    println!("{issue}");
+                }
+                if !issues.is_empty() {
+                    bail!(
+                        "found {} problem(s), run `db repair` to fix them",
+                        issues.len()
+                    );
+                }
+                println!("no problems found");
+            }
+            cli::DBCommand::Repair => {
+                let issues = DBHandle::repair_at(&db_path)?;
+                for issue in &issues {
+                    println!("fixed: {issue}");
+                }
+                if issues.is_empty() {
+                    println!("no problems found");
+                }
+            }
+        },
+        cli::Command::Recover { finish, undo } => {
+            let proj = cur_project(&DBHandle::open_at(&db_path)?)?;
+            let opts = CheckoutOptions::for_project(&proj, stash_mode, force);
+            let git = cur_git(&proj)?;
+            let Some(journal) = git.interrupted_checkout()? else {
+                println!("no interrupted checkout found");
+                return Ok(());
+            };
+            println!("{journal}");
+            let finish = if finish || undo {
+                finish
+            } else {
+                prompt_finish(&journal)?
+            };
+            if finish {
+                ui::handle_stash_conflict(&git, git.finish_checkout(&journal, &opts))?;
+            } else {
+                git.undo_checkout(&journal)?;
+            }
+        }
+        cli::Command::Worktree { command } => {
+            let mut db = DBHandle::open_at(&db_path)?;
+            let proj = cur_project(&db)?;
+            match command {
+                cli::WorktreeCommand::Enable { branch } => {
+                    db.set_worktree(proj.path.as_str(), branch.as_str(), true)?
+                }
+                cli::WorktreeCommand::Disable { branch } => {
+                    db.set_worktree(proj.path.as_str(), branch.as_str(), false)?
+                }
+                cli::WorktreeCommand::List => {
+                    for worktree in cur_git(&proj)?.worktrees()? {
+                        let head = match &worktree.branch {
+                            Some(branch) => branch.clone(),
+                            None => {
+                                format!("{} (detached)", worktree.head.get(..7).unwrap_or_default())
+                            }
+                        };
+                        let prunable = if worktree.prunable { " (prunable)" } else { "" };
+                        println!("{}  {head}{prunable}", worktree.path.display());
+                    }
+                }
+                cli::WorktreeCommand::Prune => cur_git(&proj)?.prune_worktrees()?,
+            }
+        }
+        cli::Command::ShellInit { shell } => {
+            let exe = std::env::current_exe()?;
+            print!("{}", shell::init_script(shell, exe.as_path())?);
+        }
+    }
+    Ok(())
+}
+
+// asks whether to finish or undo an interrupted checkout
+fn prompt_finish(journal: &CheckoutJournal) -> Result<bool> {
+    loop {
+        print!(
+            "[f]inish checkout to {} or [u]ndo back to {}? ",
+            journal.to_branch, journal.from_branch
+        );
+        std::io::stdout().flush()?;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            bail!("no answer given");
+        }
+        match answer.trim() {
+            "f" | "finish" => return Ok(true),
+            "u" | "undo" => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+// worktrees can live outside of the project directory, they belong
+// to the project of their main worktree.
+fn cur_project(db: &DBHandle) -> Result<Project> {
     let cwd = std::env::current_dir()?;
-    let proj = core::get_project_from_path(cwd.as_path())?;
-    Ok(proj)
+    let err = match db.project_for_dir(cwd.as_path()) {
+        Ok(proj) => return Ok(proj.clone()),
+        Err(err) => err,
+    };
+    let git = Git::new(cwd.to_str().ok_or(anyhow!("bad path"))?.to_string());
+    match git.worktrees().ok().and_then(|w| w.into_iter().next()) {
+        Some(main) => Ok(db.project_for_dir(main.path.as_path())?.clone()),
+        None => Err(err),
+    }
+}
+
+// git runs in the worktree lgc was started from when it isn't the
+// project directory.
+fn cur_git(project: &Project) -> Result<Git> {
+    let cwd = std::env::current_dir()?.canonicalize()?;
+    if cwd.starts_with(project.path.as_str()) {
+        return Ok(Git::new(project.path.clone()));
+    }
+    let toplevel = Git::new(cwd.to_str().ok_or(anyhow!("bad path"))?.to_string()).toplevel()?;
+    Ok(Git::new(
+        toplevel.to_str().ok_or(anyhow!("bad path"))?.to_string(),
+    ))
 }
@@ -0,0 +1,110 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+
+// file the shell wrapper reads the directory to cd into from
+const CD_FILE_ENV: &str = "LAZY_GIT_CHECKOUT_CD_FILE";
+
+/// shells a wrapper can be generated for
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// a function named `lgc` that runs `exe` and changes to the directory
+/// lgc asked for once it exits. a child process can't change the
+/// directory of the shell that started it, so lgc writes it to a temp
+/// file created by the wrapper instead.
+pub fn init_script(shell: Shell, exe: &Path) -> Result<String> {
+    let exe = exe.to_str().ok_or(anyhow!("bad path"))?;
+    let script = match shell {
+        Shell::Bash | Shell::Zsh => format!(
+            r#"lgc() {{
+    local cd_file ret
+    cd_file="$(mktemp)" || return
+    {CD_FILE_ENV}="$cd_file" {exe} "$@"
+    ret=$?
+    if [ -s "$cd_file" ]; then
+        cd -- "$(cat "$cd_file")" || ret=$?
+    fi
+    rm -f -- "$cd_file"
+    return $ret
+}}
+"#,
+            exe = quote_posix(exe)
+        ),
+        Shell::Fish => format!(
+            r#"function lgc
+    set -l cd_file (mktemp)
+    or return
+    env {CD_FILE_ENV}=$cd_file {exe} $argv
+    set -l ret $status
+    if test -s $cd_file
+        cd (cat $cd_file)
+        or set ret $status
+    end
+    rm -f -- $cd_file
+    return $ret
+end
+"#,
+            exe = quote_fish(exe)
+        ),
+    };
+    Ok(script)
+}
+
+/// hands `path` to the shell wrapper, or prints it as the last line of
+/// the output when lgc runs without one.
+pub fn change_dir(path: &Path) -> Result<()> {
+    match std::env::var_os(CD_FILE_ENV) {
+        Some(file) if !file.is_empty() => {
+            std::fs::write(file, path.to_str().ok_or(anyhow!("bad path"))?)?
+        }
+        _ => println!("{}", path.display()),
+    }
+    Ok(())
+}
+
+fn quote_posix(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn quote_fish(s: &str) -> String {
+    format!("'{}'", s.replace('\\', r"\\").replace('\'', r"\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bash_wrapper_changes_dir() {
+        let dir = std::env::temp_dir().join(format!("lgc-shell-test-{}", std::process::id()));
+        let target = dir.join("it's here");
+        std::fs::create_dir_all(&target).unwrap();
+        // stands in for lgc, asking to cd into `target`. it's run
+        // through sh, which the wrapper passes it to as an argument.
+        let fake_lgc = dir.join("fake lgc");
+        let fake = format!(
+            "#!/bin/sh\nprintf %s {} > \"${CD_FILE_ENV}\"\n",
+            quote_posix(target.to_str().unwrap())
+        );
+        std::fs::write(&fake_lgc, fake).unwrap();
+
+        let script = init_script(Shell::Bash, Path::new("/bin/sh")).unwrap();
+        let fake_lgc = quote_posix(fake_lgc.to_str().unwrap());
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(format!("{script}\nlgc {fake_lgc} && pwd"))
+            .output()
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(output.status.success());
+        assert_eq!(
+            String::from_utf8(output.stdout).unwrap().trim_end(),
+            target.to_str().unwrap()
+        );
+    }
+}
@@ -1,9 +1,11 @@
 use std::{
-    io::{self, Write},
-    time::{Duration, Instant},
+    cell::RefCell,
+    io::{self, Stdout},
+    path::{Path, PathBuf},
+    rc::Rc,
 };
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
 use crossterm::{
     event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEventKind},
     execute,
@@ -16,8 +18,12 @@ use ratatui::{
 };
 
 use crate::{
-    core::{self, Project},
-    widgets::{AddBranchWidget, ChangeBranchesWidget, ChangeBranchesWidgetMode, ExitContextResult},
+    core::{self, DBHandle, Project},
+    shell,
+    widgets::{
+        AddBranchWidget, ChangeBranchesWidget, ChangeBranchesWidgetMode, ExitContextResult,
+        ProjectEntry, ProjectsWidget, ResolveConflictWidget, StashesWidget,
+    },
 };
 
 macro_rules! continue_after {
@@ -32,41 +38,90 @@ type ShouldExit = bool;
 enum Mode {
     Checkout,
     Add,
+    Stashes,
 }
 
 struct UI {
     mode: Mode,
+    git: core::Git,
 
     change_branches_widget: ChangeBranchesWidget,
     add_branches_widget: AddBranchWidget,
+    stashes_widget: StashesWidget,
 
     exit_with_checkout: bool,
+    // exits to the project picker
+    switch_project: bool,
+    // directory to cd into on exit, set when the project was picked
+    enter_dir: Option<PathBuf>,
 }
 
 impl UI {
-    fn new(project: &Project, git: core::Git) -> Result<UI> {
+    fn new(
+        db: Rc<RefCell<DBHandle>>,
+        project: &Project,
+        git: core::Git,
+        opts: core::CheckoutOptions,
+    ) -> Result<UI> {
         let branches = git.all_project_branches()?;
-        let saved_branches = project
-            .branches
-            .iter()
-            .map(|b| b.name.clone())
-            .collect::<Vec<String>>();
+        let saved_branches = project.branches.clone();
 
         Ok(UI {
             mode: Mode::Checkout,
+            stashes_widget: StashesWidget::new(db.clone(), project.path.clone(), git.clone()),
             change_branches_widget: ChangeBranchesWidget::new(
+                db.clone(),
                 project.path.clone(),
                 saved_branches.clone(),
-                git,
+                git.clone(),
+                opts,
             )?,
-            add_branches_widget: AddBranchWidget::new(project.path.clone(), branches),
+            add_branches_widget: AddBranchWidget::new(
+                db,
+                git.clone(),
+                project.path.clone(),
+                branches,
+            ),
             exit_with_checkout: false,
+            switch_project: false,
+            enter_dir: None,
+            git,
         })
     }
 
     fn on_char(&mut self, c: char) -> Result<ShouldExit> {
         match self.mode {
+            Mode::Add if self.add_branches_widget.is_confirming() => match c {
+                'y' => {
+                    self.add_branches_widget.confirm_tracking()?;
+                    continue_after!(self.finish_add()?)
+                }
+                'n' => continue_after!(self.add_branches_widget.cancel_tracking()),
+                _ => Ok(false),
+            },
             Mode::Add => continue_after!(self.add_branches_widget.input_char(c)),
+            Mode::Checkout if self.change_branches_widget.is_confirming() => match c {
+                'y' => {
+                    self.change_branches_widget.confirm_force();
+                    self.exit_with_checkout = true;
+                    Ok(true)
+                }
+                'n' => continue_after!(self.change_branches_widget.cancel_force()),
+                _ => Ok(false),
+            },
+            Mode::Stashes if self.stashes_widget.is_editing() => {
+                continue_after!(self.stashes_widget.input_char(c))
+            }
+            Mode::Stashes => match c {
+                'j' => self.on_down(),
+                'k' => self.on_up(),
+                'a' => continue_after!(self.stashes_widget.apply_selected()?),
+                'p' => continue_after!(self.stashes_widget.pop_selected()?),
+                'd' => continue_after!(self.stashes_widget.drop_selected()?),
+                'n' => continue_after!(self.stashes_widget.start_rename()),
+                'm' => continue_after!(self.stashes_widget.start_move()),
+                _ => Ok(false),
+            },
             Mode::Checkout => match self.change_branches_widget.mode {
                 ChangeBranchesWidgetMode::Search => {
                     continue_after!(self.change_branches_widget.input_char(c))
@@ -74,6 +129,10 @@ impl UI {
                 ChangeBranchesWidgetMode::Normal => match c {
                     'q' => Ok(true),
                     'a' => continue_after!(self.mode = Mode::Add),
+                    's' => {
+                        self.stashes_widget.reload()?;
+                        continue_after!(self.mode = Mode::Stashes)
+                    }
                     '?' => continue_after!(
                         self.change_branches_widget.mode = ChangeBranchesWidgetMode::Search
                     ),
@@ -82,6 +141,13 @@ impl UI {
                     'J' => continue_after!(self.change_branches_widget.swap_down()?),
                     'K' => continue_after!(self.change_branches_widget.swap_up()?),
                     'r' => continue_after!(self.change_branches_widget.remove_selected()?),
+                    'w' => {
+                        continue_after!(self.change_branches_widget.toggle_worktree_selected()?)
+                    }
+                    'p' => {
+                        self.switch_project = true;
+                        Ok(true)
+                    }
                     _ => Ok(false),
                 },
             },
@@ -92,6 +158,7 @@ impl UI {
         match self.mode {
             Mode::Add => self.add_branches_widget.remove_char(),
             Mode::Checkout => self.change_branches_widget.remove_char(),
+            Mode::Stashes => self.stashes_widget.remove_char(),
         }
         Ok(false)
     }
@@ -99,18 +166,29 @@ impl UI {
     fn on_enter(&mut self) -> Result<ShouldExit> {
         match self.mode {
             Mode::Add => {
-                self.add_branches_widget.add_branch()?;
-                self.change_branches_widget.reload_saved_branches()?;
-                self.mode = Mode::Checkout;
+                if self.add_branches_widget.add_branch()? {
+                    self.finish_add()?;
+                }
                 Ok(false)
             }
+            // a merge, rebase, etc. in progress has to be confirmed first
+            Mode::Checkout if self.change_branches_widget.needs_force() => {
+                continue_after!(self.change_branches_widget.start_confirm_force())
+            }
             Mode::Checkout => {
                 self.exit_with_checkout = true;
                 Ok(true)
             }
+            Mode::Stashes => continue_after!(self.stashes_widget.confirm_input()?),
         }
     }
 
+    fn finish_add(&mut self) -> Result<()> {
+        self.change_branches_widget.reload_saved_branches()?;
+        self.mode = Mode::Checkout;
+        Ok(())
+    }
+
     fn on_esc(&mut self) -> Result<ShouldExit> {
         match self.mode {
             Mode::Add => match self.add_branches_widget.exit_context() {
@@ -119,6 +197,16 @@ impl UI {
                 }
                 ExitContextResult::Continue => {}
             },
+            Mode::Stashes => match self.stashes_widget.exit_context() {
+                ExitContextResult::Exit => {
+                    self.change_branches_widget.clear_previews();
+                    self.mode = Mode::Checkout;
+                }
+                ExitContextResult::Continue => {}
+            },
+            Mode::Checkout if self.change_branches_widget.is_confirming() => {
+                self.change_branches_widget.cancel_force();
+            }
             Mode::Checkout => match self.change_branches_widget.mode {
                 ChangeBranchesWidgetMode::Normal => return Ok(false),
                 ChangeBranchesWidgetMode::Search => {
@@ -135,6 +223,7 @@ impl UI {
         match self.mode {
             Mode::Add => self.add_branches_widget.previous(),
             Mode::Checkout => self.change_branches_widget.previous(),
+            Mode::Stashes => self.stashes_widget.previous(),
         }
         Ok(false)
     }
@@ -143,6 +232,21 @@ impl UI {
         match self.mode {
             Mode::Add => self.add_branches_widget.next(),
             Mode::Checkout => self.change_branches_widget.next(),
+            Mode::Stashes => self.stashes_widget.next(),
+        }
+        Ok(false)
+    }
+
+    fn on_page_up(&mut self) -> Result<ShouldExit> {
+        if let Mode::Checkout = self.mode {
+            self.change_branches_widget.scroll_preview_up();
+        }
+        Ok(false)
+    }
+
+    fn on_page_down(&mut self) -> Result<ShouldExit> {
+        if let Mode::Checkout = self.mode {
+            self.change_branches_widget.scroll_preview_down();
         }
         Ok(false)
     }
@@ -169,27 +273,46 @@ impl UI {
         self.exit_with_checkout
     }
 
+    // a picked project is entered also when leaving without checking
+    // out, a worktree checked out after it takes its place.
     fn run_exit_hooks(&self) -> Result<()> {
+        if let Some(dir) = &self.enter_dir {
+            shell::change_dir(dir.as_path())?;
+        }
         if self.exit_with_checkout {
-            self.change_branches_widget.checkout_selected()?;
+            if let Some(worktree) = self.change_branches_widget.checkout_selected()? {
+                shell::change_dir(worktree.as_path())?;
+            }
         };
         Ok(())
     }
 }
 
-pub fn start_ui(project: Project, git: core::Git) -> Result<()> {
+type CrosstermTerminal = Terminal<CrosstermBackend<Stdout>>;
+
+// where the ui reads its input from
+trait EventSource {
+    // blocks until the next event, None ends the ui
+    fn next_event(&mut self) -> Result<Option<Event>>;
+}
+
+struct TerminalEvents;
+
+impl EventSource for TerminalEvents {
+    fn next_event(&mut self) -> Result<Option<Event>> {
+        Ok(Some(event::read()?))
+    }
+}
+
+fn setup_terminal() -> Result<CrosstermTerminal> {
     enable_raw_mode()?;
     let mut stdout = io::stdout();
     execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
     let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
-
-    // create app and run it
-    let tick_rate = Duration::from_millis(250);
-    let mut app = UI::new(&project, git)?;
-    let res = run_ui(&mut terminal, &mut app, tick_rate);
+    Ok(Terminal::new(backend)?)
+}
 
-    // restore terminal
+fn restore_terminal(terminal: &mut CrosstermTerminal) -> Result<()> {
     disable_raw_mode()?;
     execute!(
         terminal.backend_mut(),
@@ -197,22 +320,286 @@ pub fn start_ui(project: Project, git: core::Git) -> Result<()> {
         DisableMouseCapture
     )?;
     terminal.show_cursor()?;
+    Ok(())
+}
+
+// `stash_mode` overrides the one saved for the project, also for the
+// projects picked from the project picker.
+pub fn start_ui(
+    db: DBHandle,
+    project: Project,
+    git: core::Git,
+    stash_mode: Option<core::StashMode>,
+    force: bool,
+) -> Result<()> {
+    start(db, Some((project, git)), stash_mode, force)
+}
+
+// starts on the project picker, for when lgc runs outside of any
+// project.
+pub fn start_project_picker(
+    db: DBHandle,
+    stash_mode: Option<core::StashMode>,
+    force: bool,
+) -> Result<()> {
+    start(db, None, stash_mode, force)
+}
+
+fn start(
+    db: DBHandle,
+    project: Option<(Project, core::Git)>,
+    stash_mode: Option<core::StashMode>,
+    force: bool,
+) -> Result<()> {
+    let mut terminal = setup_terminal()?;
+
+    // create app and run it
+    let res = run_app(
+        &mut terminal,
+        &mut TerminalEvents,
+        Rc::new(RefCell::new(db)),
+        project,
+        stash_mode,
+        force,
+        &core::Git::new,
+    );
+
+    restore_terminal(&mut terminal)?;
+
+    match res {
+        Err(err) => println!("{err:?}"),
+        Ok(Some(app)) => handle_stash_conflict(&app.git, app.run_exit_hooks())?,
+        Ok(None) => {}
+    }
+
+    Ok(())
+}
+
+// runs the ui of `project`, or the project picker when there is none,
+// moving between them until one exits. returns the ui of the last
+// project entered to run its exit hooks.
+fn run_app<B: Backend>(
+    terminal: &mut Terminal<B>,
+    events: &mut impl EventSource,
+    db: Rc<RefCell<DBHandle>>,
+    project: Option<(Project, core::Git)>,
+    stash_mode: Option<core::StashMode>,
+    force: bool,
+    make_git: &dyn Fn(String) -> core::Git,
+) -> Result<Option<UI>> {
+    let new_ui = |project: &Project, git: core::Git| {
+        let opts = core::CheckoutOptions::for_project(project, stash_mode, force);
+        UI::new(db.clone(), project, git, opts)
+    };
+    let mut app = match project {
+        Some((project, git)) => Some(new_ui(&project, git)?),
+        None => None,
+    };
+    loop {
+        if let Some(mut ui) = app.take() {
+            run_ui(terminal, &mut ui, events)?;
+            if !ui.switch_project {
+                return Ok(Some(ui));
+            }
+            ui.switch_project = false;
+            app = Some(ui);
+        }
+        let entries = project_entries(&db.borrow(), make_git);
+        let mut picker = ProjectsWidget::new(entries);
+        // a project that can't be opened keeps the picker open
+        let picked = loop {
+            let Some(project) = run_picker(terminal, &mut picker, events)? else {
+                break None;
+            };
+            match new_ui(&project, make_git(project.path.clone())) {
+                Ok(ui) => break Some((project, ui)),
+                Err(err) => picker.set_message(format!("could not open {}: {err}", project.path)),
+            }
+        };
+        match picked {
+            Some((project, mut ui)) => {
+                ui.enter_dir = Some(PathBuf::from(project.path.as_str()));
+                app = Some(ui);
+            }
+            // back to the project the picker was opened from
+            None if app.is_some() => {}
+            None => return Ok(None),
+        }
+    }
+}
+
+// checked out branch and changes of every project in the db
+fn project_entries(db: &DBHandle, make_git: &dyn Fn(String) -> core::Git) -> Vec<ProjectEntry> {
+    db.projects()
+        .iter()
+        .map(|project| {
+            let git = make_git(project.path.clone());
+            ProjectEntry {
+                project: project.clone(),
+                branch: git.get_current_branch().ok(),
+                dirty: git.has_changes().unwrap_or(false),
+            }
+        })
+        .collect()
+}
+
+// returns the picked project, None if the picker was left
+fn run_picker<B: Backend>(
+    terminal: &mut Terminal<B>,
+    picker: &mut ProjectsWidget,
+    events: &mut impl EventSource,
+) -> Result<Option<Project>> {
+    loop {
+        terminal.draw(|f| picker.draw(f, f.size()))?;
+        let Some(event) = events.next_event()? else {
+            return Ok(None);
+        };
+        let Event::Key(key) = event else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match key.code {
+            KeyCode::Enter => return Ok(picker.selected().cloned()),
+            KeyCode::Esc => {
+                if let ExitContextResult::Exit = picker.exit_context() {
+                    return Ok(None);
+                }
+            }
+            KeyCode::Char(c) => picker.input_char(c),
+            KeyCode::Backspace => picker.remove_char(),
+            KeyCode::Down => picker.next(),
+            KeyCode::Up => picker.previous(),
+            _ => {}
+        }
+    }
+}
+
+// opens the conflict screen if `res` failed because popping a stash
+// conflicted, any other error is returned as is.
+pub fn handle_stash_conflict(git: &core::Git, res: Result<()>) -> Result<()> {
+    let Err(err) = res else {
+        return Ok(());
+    };
+    match err.downcast::<core::StashConflict>() {
+        Ok(conflict) => start_conflict_ui(git, conflict),
+        Err(err) => Err(err),
+    }
+}
+
+enum ConflictAction {
+    Continue,
+    Exit(String),
+    Edit(PathBuf),
+}
+
+struct ConflictUI<'a> {
+    git: &'a core::Git,
+    widget: ResolveConflictWidget,
+}
+
+impl ConflictUI<'_> {
+    fn on_key(&mut self, code: KeyCode) -> Result<ConflictAction> {
+        let action = match code {
+            KeyCode::Char('q') | KeyCode::Esc => ConflictAction::Exit(format!(
+                "conflict left unresolved, the stash for {} is kept",
+                self.widget.conflict().branch
+            )),
+            KeyCode::Char('j') | KeyCode::Down => {
+                self.widget.next();
+                ConflictAction::Continue
+            }
+            KeyCode::Char('k') | KeyCode::Up => {
+                self.widget.previous();
+                ConflictAction::Continue
+            }
+            KeyCode::Char('e') | KeyCode::Enter => match self.widget.selected_file() {
+                Some(file) => ConflictAction::Edit(self.git.toplevel()?.join(file)),
+                None => ConflictAction::Continue,
+            },
+            KeyCode::Char('r') => match self.git.resolve_conflict(self.widget.conflict()) {
+                Ok(()) => ConflictAction::Exit(format!(
+                    "conflict resolved, the stash for {} was dropped",
+                    self.widget.conflict().branch
+                )),
+                Err(err) => {
+                    self.widget.set_message(format!("{err}"));
+                    ConflictAction::Continue
+                }
+            },
+            KeyCode::Char('a') => {
+                self.git.abort_conflict()?;
+                ConflictAction::Exit(format!(
+                    "conflict aborted, the stash for {} is kept and popped on its next checkout",
+                    self.widget.conflict().branch
+                ))
+            }
+            _ => ConflictAction::Continue,
+        };
+        Ok(action)
+    }
+}
+
+pub fn start_conflict_ui(git: &core::Git, conflict: core::StashConflict) -> Result<()> {
+    let mut terminal = setup_terminal()?;
+    let mut app = ConflictUI {
+        git,
+        widget: ResolveConflictWidget::new(conflict),
+    };
+    let res = run_conflict_ui(&mut terminal, &mut app);
+    restore_terminal(&mut terminal)?;
+    println!("{}", res?);
+    Ok(())
+}
 
-    if let Err(err) = res {
-        println!("{err:?}");
-    } else {
-        app.run_exit_hooks()?;
+fn run_conflict_ui(terminal: &mut CrosstermTerminal, app: &mut ConflictUI) -> Result<String> {
+    loop {
+        terminal.draw(|f| app.widget.draw(f, f.size()))?;
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        match app.on_key(key.code)? {
+            ConflictAction::Continue => {}
+            ConflictAction::Exit(message) => return Ok(message),
+            ConflictAction::Edit(path) => {
+                restore_terminal(terminal)?;
+                let res = open_editor(path.as_path());
+                *terminal = setup_terminal()?;
+                terminal.clear()?;
+                if let Err(err) = res {
+                    app.widget.set_message(format!("{err}"));
+                }
+            }
+        }
     }
+}
 
+// opens `path` with $VISUAL or $EDITOR, falling back to vi
+fn open_editor(path: &Path) -> Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let mut parts = editor.split_whitespace();
+    let program = parts.next().ok_or(anyhow!("empty editor command"))?;
+    let status = std::process::Command::new(program)
+        .args(parts)
+        .arg(path)
+        .status()?;
+    if !status.success() {
+        bail!("{editor} exited with {status}");
+    }
     Ok(())
 }
 
-fn run_ui<B: Backend + Write>(
+fn run_ui<B: Backend>(
     terminal: &mut Terminal<B>,
     app: &mut UI,
-    tick_rate: Duration,
+    events: &mut impl EventSource,
 ) -> Result<()> {
-    let mut last_tick = Instant::now();
     loop {
         // TODO: this exit check should be internal to UI
         if app.exit() {
@@ -221,22 +608,19 @@ fn run_ui<B: Backend + Write>(
 
         terminal.draw(|f| draw(f, app))?;
 
-        let timeout = tick_rate.saturating_sub(last_tick.elapsed());
-        if crossterm::event::poll(timeout)? {
-            match handle_input(app) {
-                Ok(true) => return Ok(()),
-                Err(err) => bail!(err),
-                Ok(false) => {} // continue running
-            }
-        }
-        if last_tick.elapsed() >= tick_rate {
-            last_tick = Instant::now();
+        let Some(event) = events.next_event()? else {
+            return Ok(());
+        };
+        match handle_event(app, event) {
+            Ok(true) => return Ok(()),
+            Err(err) => bail!(err),
+            Ok(false) => {} // continue running
         }
     }
 }
 
-fn handle_input(app: &mut UI) -> Result<ShouldExit> {
-    if let Event::Key(key) = event::read()? {
+fn handle_event(app: &mut UI, event: Event) -> Result<ShouldExit> {
+    if let Event::Key(key) = event {
         if key.kind == KeyEventKind::Press {
             return if key.modifiers == crossterm::event::KeyModifiers::SHIFT {
                 match key.code {
@@ -253,6 +637,8 @@ fn handle_input(app: &mut UI) -> Result<ShouldExit> {
                     KeyCode::Backspace => app.on_backspace(),
                     KeyCode::Down => app.on_down(),
                     KeyCode::Up => app.on_up(),
+                    KeyCode::PageDown => app.on_page_down(),
+                    KeyCode::PageUp => app.on_page_up(),
                     _ => Ok(false),
                 }
             };
@@ -269,5 +655,557 @@ fn draw(f: &mut Frame, app: &mut UI) {
     match app.mode {
         Mode::Add => app.add_branches_widget.draw(f, screen),
         Mode::Checkout => app.change_branches_widget.draw(f, screen),
+        Mode::Stashes => app.stashes_widget.draw(f, screen),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::VecDeque, rc::Rc};
+
+    use crossterm::event::{KeyEvent, KeyModifiers};
+    use ratatui::backend::TestBackend;
+
+    use super::*;
+    use crate::backend::FakeBackend;
+
+    // replays a fixed list of events, ending the ui once they run out
+    struct ScriptedEvents(VecDeque<Event>);
+
+    impl EventSource for ScriptedEvents {
+        fn next_event(&mut self) -> Result<Option<Event>> {
+            Ok(self.0.pop_front())
+        }
+    }
+
+    fn key(code: KeyCode) -> Event {
+        let modifiers = match code {
+            KeyCode::Char(c) if c.is_uppercase() => KeyModifiers::SHIFT,
+            _ => KeyModifiers::NONE,
+        };
+        Event::Key(KeyEvent::new(code, modifiers))
+    }
+
+    fn typed(text: &str) -> Vec<Event> {
+        text.chars().map(|c| key(KeyCode::Char(c))).collect()
+    }
+
+    // db in its own directory with a single project saved with
+    // `branches`, removed when dropped.
+    struct TestDB {
+        dir: PathBuf,
+        db: Rc<RefCell<DBHandle>>,
+        project: Project,
+    }
+
+    impl TestDB {
+        fn new(name: &str, branches: &[&str]) -> TestDB {
+            let dir =
+                std::env::temp_dir().join(format!("lgc-ui-test-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let mut db = DBHandle::open_at(&dir.join("db.json")).unwrap();
+            let path = format!("/lgc/{name}");
+            db.add_project(path.as_str()).unwrap();
+            for branch in branches {
+                db.add_branch(path.as_str(), branch.to_string()).unwrap();
+            }
+            let project = db.project(path.as_str()).unwrap().clone();
+            TestDB {
+                dir,
+                db: Rc::new(RefCell::new(db)),
+                project,
+            }
+        }
+
+        fn saved_branches(&self) -> Vec<String> {
+            let db = DBHandle::open_at(self.db.borrow().path()).unwrap();
+            db.project(self.project.path.as_str())
+                .unwrap()
+                .branches
+                .iter()
+                .map(|b| b.name.clone())
+                .collect()
+        }
+
+        // a second project, /lgc/other, with main and dev saved
+        fn add_other_project(&self) {
+            let mut db = self.db.borrow_mut();
+            db.add_project("/lgc/other").unwrap();
+            for branch in ["main", "dev"] {
+                db.add_branch("/lgc/other", branch.to_string()).unwrap();
+            }
+        }
+
+        fn git(&self, path: String) -> core::Git {
+            let fake = Rc::new(FakeBackend::new(
+                self.dir.clone(),
+                &[
+                    "main",
+                    "dev",
+                    "feature/login",
+                    "feature/logout",
+                    "remotes/origin/feature/remote",
+                ],
+            ));
+            core::Git::with_backend(path, fake)
+        }
+
+        // runs the ui on the project through `events` and returns the
+        // text of the last frame drawn.
+        fn render(&self, events: Vec<Event>) -> String {
+            let git = self.git(self.project.path.clone());
+            let project = Some((self.project.clone(), git));
+            self.run(project, None, events, &|p| self.git(p)).1
+        }
+
+        // like render, but starts on the project picker when there is
+        // no `project` and returns the ui of the project left on.
+        fn run(
+            &self,
+            project: Option<(Project, core::Git)>,
+            stash_mode: Option<core::StashMode>,
+            events: Vec<Event>,
+            make_git: &dyn Fn(String) -> core::Git,
+        ) -> (Option<UI>, String) {
+            let mut terminal = Terminal::new(TestBackend::new(60, 12)).unwrap();
+            let mut events = ScriptedEvents(events.into());
+            let app = run_app(
+                &mut terminal,
+                &mut events,
+                self.db.clone(),
+                project,
+                stash_mode,
+                false,
+                make_git,
+            )
+            .unwrap();
+            let buffer = terminal.backend().buffer();
+            let text = (0..buffer.area.height)
+                .map(|y| {
+                    (0..buffer.area.width)
+                        .map(|x| buffer.get(x, y).symbol())
+                        .collect::<String>()
+                        .trim_end()
+                        .to_string()
+                })
+                .collect::<Vec<String>>()
+                .join("\n");
+            (app, text)
+        }
+    }
+
+    impl Drop for TestDB {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn search_selects_first_match() {
+        let db = TestDB::new("search", &["main", "dev", "feature/login"]);
+        let mut events = vec![key(KeyCode::Char('?'))];
+        events.extend(typed("fe"));
+        assert_eq!(
+            db.render(events),
+            [
+                "┌searching─────────────────────────────────────────────────┐",
+                "│fe                                                        │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│   main *             ││last commit on feature/login      │",
+                "│   dev                ││author:   lgc                     │",
+                "│>> feature/login      ││date:     2 days ago              │",
+                "│                      ││upstream: none                    │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││no pending changes                │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn add_filters_branches() {
+        let db = TestDB::new("add-filter", &["main"]);
+        let mut events = vec![key(KeyCode::Char('a'))];
+        events.extend(typed("log"));
+        events.push(key(KeyCode::Down));
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Add branch────────────────────────────────────────────────┐",
+                "│log                                                       │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────────────────────────────────────────┐",
+                "│>> local  feature/login                                   │",
+                "│   local  feature/logout                                  │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "└──────────────────────────────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn add_saves_selected_branch() {
+        let db = TestDB::new("add", &["main"]);
+        let mut events = vec![key(KeyCode::Char('a'))];
+        events.extend(typed("logout"));
+        events.extend([key(KeyCode::Down), key(KeyCode::Enter)]);
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/add                                                  │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│main *                ││                                  │",
+                "│feature/logout        ││                                  │",
+                "│                      ││                                  │",
+                "│                      ││                                  │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││                                  │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+        assert_eq!(db.saved_branches(), vec!["main", "feature/logout"]);
+    }
+
+    #[test]
+    fn reorder_moves_selected_branch() {
+        let db = TestDB::new("reorder", &["main", "dev", "feature/login"]);
+        let events = vec![key(KeyCode::Char('j')), key(KeyCode::Char('J'))];
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/reorder                                              │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│   dev                ││last commit on main               │",
+                "│>> main *             ││author:   lgc                     │",
+                "│   feature/login      ││date:     2 days ago              │",
+                "│                      ││upstream: none                    │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││no pending changes                │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+        assert_eq!(db.saved_branches(), vec!["dev", "main", "feature/login"]);
+    }
+
+    #[test]
+    fn remove_deletes_selected_branch() {
+        let db = TestDB::new("remove", &["main", "dev", "feature/login"]);
+        let events = vec![
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('r')),
+        ];
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/remove                                               │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│main *                ││                                  │",
+                "│feature/login         ││                                  │",
+                "│                      ││                                  │",
+                "│                      ││                                  │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││                                  │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+        assert_eq!(db.saved_branches(), vec!["main", "feature/login"]);
+    }
+
+    #[test]
+    fn add_remote_branch_asks_to_track_it() {
+        let db = TestDB::new("add-remote", &["main"]);
+        let mut events = vec![key(KeyCode::Char('a'))];
+        events.extend(typed("remote"));
+        events.extend([key(KeyCode::Down), key(KeyCode::Enter)]);
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Create branch feature/remote tracking origin/feature/remot┐",
+                "│remote                                                    │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────────────────────────────────────────┐",
+                "│>> remote origin/feature/remote                           │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "└──────────────────────────────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn add_remote_branch_saves_local_name() {
+        let db = TestDB::new("add-remote-confirm", &["main"]);
+        let mut events = vec![key(KeyCode::Char('a'))];
+        events.extend(typed("remote"));
+        events.extend([
+            key(KeyCode::Down),
+            key(KeyCode::Enter),
+            key(KeyCode::Char('y')),
+        ]);
+        db.render(events);
+        assert_eq!(db.saved_branches(), vec!["main", "feature/remote"]);
+    }
+
+    #[test]
+    fn tags_and_commits_are_labeled() {
+        let mut db = TestDB::new("targets", &["main"]);
+        let targets = [
+            ("v1.0", core::TargetKind::Tag),
+            (
+                "0123456789abcdef0123456789abcdef01234567",
+                core::TargetKind::Commit,
+            ),
+        ];
+        for (name, kind) in targets {
+            let target = core::Branch {
+                kind,
+                ..core::Branch::new(name.to_string())
+            };
+            db.db
+                .borrow_mut()
+                .add_target(db.project.path.as_str(), target)
+                .unwrap();
+        }
+        db.project = db
+            .db
+            .borrow()
+            .project(db.project.path.as_str())
+            .unwrap()
+            .clone();
+        assert_eq!(
+            db.render(vec![]),
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/targets                                              │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│main *                ││                                  │",
+                "│v1.0 tag              ││                                  │",
+                "│0123456 commit        ││                                  │",
+                "│                      ││                                  │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││                                  │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn checkout_during_rebase_asks_first() {
+        let db = TestDB::new("rebase", &["main", "dev"]);
+        std::fs::create_dir(db.dir.join("rebase-merge")).unwrap();
+        let events = vec![
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('j')),
+            key(KeyCode::Enter),
+        ];
+        assert_eq!(
+            db.render(events),
+            [
+                "┌A rebase is in progress, check out anyway? (y/n)──────────┐",
+                "│/lgc/rebase                                               │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│   main *             ││last commit on dev                │",
+                "│>> dev                ││author:   lgc                     │",
+                "│                      ││date:     2 days ago              │",
+                "│                      ││upstream: none                    │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││no pending changes                │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn worktree_toggles_selected_branch() {
+        let db = TestDB::new("worktree", &["main", "dev"]);
+        let events = vec![
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('w')),
+        ];
+        assert_eq!(
+            db.render(events),
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/worktree                                             │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│   main *             ││last commit on dev                │",
+                "│>> dev worktree       ││author:   lgc                     │",
+                "│                      ││date:     2 days ago              │",
+                "│                      ││upstream: none                    │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││no pending changes                │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+        let saved = DBHandle::open_at(db.db.borrow().path()).unwrap();
+        let branches = &saved.project(db.project.path.as_str()).unwrap().branches;
+        assert_eq!(
+            branches.iter().map(|b| b.worktree).collect::<Vec<bool>>(),
+            vec![false, true]
+        );
+    }
+
+    #[test]
+    fn projects_show_branch_and_changes() {
+        let db = TestDB::new("picker", &["main"]);
+        db.db.borrow_mut().add_project("/lgc/other").unwrap();
+        let make_git = |path: String| {
+            let git = db.git(path.clone());
+            if path == "/lgc/other" {
+                let fake = FakeBackend::new(db.dir.clone(), &["dev"]);
+                fake.repo().changes.push("M file".to_string());
+                return core::Git::with_backend(path, Rc::new(fake));
+            }
+            git
+        };
+        let (app, text) = db.run(None, None, typed("lgc"), &make_git);
+        assert!(app.is_none());
+        assert_eq!(
+            text,
+            [
+                "┌Projects──────────────────────────────────────────────────┐",
+                "│lgc                                                       │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌──────────────────────────────────────────────────────────┐",
+                "│>> /lgc/picker main                                       │",
+                "│   /lgc/other dev modified                                │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "└──────────────────────────────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+    }
+
+    #[test]
+    fn unreadable_project_stays_in_picker() {
+        let db = TestDB::new("unreadable", &["main"]);
+        db.db.borrow_mut().add_project("/lgc/missing").unwrap();
+        let make_git = |path: String| match path.as_str() {
+            "/lgc/missing" => core::Git::new(path),
+            _ => db.git(path),
+        };
+        let mut events = typed("mis");
+        events.push(key(KeyCode::Enter));
+        let (app, text) = db.run(None, None, events, &make_git);
+        assert!(app.is_none());
+        let lines = text.lines().collect::<Vec<&str>>();
+        assert_eq!(
+            lines[..10].join("\n"),
+            [
+                "┌Projects──────────────────────────────────────────────────┐",
+                "│mis                                                       │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌──────────────────────────────────────────────────────────┐",
+                "│>> /lgc/missing not found                                 │",
+                "│                                                          │",
+                "│                                                          │",
+                "│                                                          │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌──────────────────────────────────────────────────────────┐",
+            ]
+            .join("\n")
+        );
+        // the error itself depends on the git backend
+        assert!(lines[10].starts_with("│could not open /lgc/missing: "));
+    }
+
+    #[test]
+    fn picked_project_is_entered() {
+        let db = TestDB::new("picked", &["main"]);
+        db.add_other_project();
+        let mut events = vec![key(KeyCode::Char('p'))];
+        events.extend(typed("oth"));
+        events.push(key(KeyCode::Enter));
+        let git = db.git(db.project.path.clone());
+        let project = Some((db.project.clone(), git));
+        let (app, text) = db.run(project, None, events, &|p| db.git(p));
+        assert_eq!(
+            text,
+            [
+                "┌Change branches───────────────────────────────────────────┐",
+                "│/lgc/other                                                │",
+                "└──────────────────────────────────────────────────────────┘",
+                "┌Branches──────────────┐┌Details───────────────────────────┐",
+                "│main *                ││                                  │",
+                "│dev                   ││                                  │",
+                "│                      ││                                  │",
+                "│                      ││                                  │",
+                "│                      │└──────────────────────────────────┘",
+                "│                      │┌Pending changes───────────────────┐",
+                "│                      ││                                  │",
+                "└──────────────────────┘└──────────────────────────────────┘",
+            ]
+            .join("\n")
+        );
+        assert_eq!(app.unwrap().enter_dir, Some(PathBuf::from("/lgc/other")));
+    }
+
+    #[test]
+    fn picked_project_honors_stash_mode_override() {
+        let db = TestDB::new("override", &["main"]);
+        db.add_other_project();
+        let other = Rc::new(FakeBackend::new(db.dir.clone(), &["main", "dev"]));
+        other.repo().changes.push("?? new".to_string());
+        let make_git = |path: String| match path.as_str() {
+            "/lgc/other" => core::Git::with_backend(path, other.clone()),
+            _ => db.git(path),
+        };
+        let mut events = vec![key(KeyCode::Char('p'))];
+        events.extend(typed("oth"));
+        events.extend([
+            key(KeyCode::Enter),
+            key(KeyCode::Char('j')),
+            key(KeyCode::Char('j')),
+            key(KeyCode::Enter),
+        ]);
+        let git = db.git(db.project.path.clone());
+        let project = Some((db.project.clone(), git));
+        let stash_mode = Some(core::StashMode::IncludeUntracked);
+        let (app, _) = db.run(project, stash_mode, events, &make_git);
+
+        app.unwrap().run_exit_hooks().unwrap();
+        assert_eq!(other.repo().head, "dev");
+        assert_eq!(other.repo().stashes.len(), 1);
+        assert!(other.repo().changes.is_empty());
     }
 }
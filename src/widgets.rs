@@ -1,8 +1,10 @@
+use std::{cell::RefCell, collections::HashMap, path::PathBuf, rc::Rc};
+
 use anyhow::{anyhow, Result};
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::Text,
+    text::{Line, Span, Text},
     widgets::{Block, Borders, List, ListState, Paragraph},
     Frame,
 };
@@ -72,44 +74,6 @@ impl<T> StatefulList<T> {
     pub fn items(&self) -> &Vec<T> {
         &self.items
     }
-
-    pub fn swap_down(&mut self) {
-        if self.items.is_empty() {
-            return;
-        }
-        let (cur, next) = match self.state.selected() {
-            Some(i) => {
-                if i >= self.items.len() - 1 {
-                    (i, 0)
-                } else {
-                    (i, i + 1)
-                }
-            }
-            None => return,
-        };
-        let item = self.items.remove(cur);
-        self.items.insert(next, item);
-        self.state.select(Some(next));
-    }
-
-    pub fn swap_up(&mut self) {
-        if self.items.is_empty() {
-            return;
-        }
-        let (cur, next) = match self.state.selected() {
-            Some(i) => {
-                if i == 0 {
-                    (i, self.items.len() - 1)
-                } else {
-                    (i, i - 1)
-                }
-            }
-            None => return,
-        };
-        let item = self.items.remove(cur);
-        self.items.insert(next, item);
-        self.state.select(Some(next));
-    }
 }
 
 pub enum ExitContextResult {
@@ -118,38 +82,92 @@ pub enum ExitContextResult {
 }
 
 pub struct AddBranchWidget {
+    db: Rc<RefCell<core::DBHandle>>,
+    git: core::Git,
     project_path: String,
-    all_branches: Vec<String>,
+    all_branches: Vec<core::BranchRef>,
     add_branch_input: String,
-    add_branch_autocomplete: StatefulList<String>,
+    add_branch_autocomplete: StatefulList<core::BranchRef>,
+    // remote branch waiting for confirmation to create a local branch
+    tracking: Option<core::RemoteBranch>,
 }
 
 impl AddBranchWidget {
-    pub fn new(project_path: String, all_branches: Vec<String>) -> AddBranchWidget {
+    pub fn new(
+        db: Rc<RefCell<core::DBHandle>>,
+        git: core::Git,
+        project_path: String,
+        all_branches: Vec<core::BranchRef>,
+    ) -> AddBranchWidget {
+        // remote HEADs only point to another remote branch
+        let all_branches = all_branches
+            .into_iter()
+            .filter(|b| b.kind != core::BranchKind::Head)
+            .collect::<Vec<core::BranchRef>>();
         AddBranchWidget {
+            db,
+            git,
             project_path,
             all_branches: all_branches.clone(),
             add_branch_input: String::new(),
             add_branch_autocomplete: StatefulList::with_items(all_branches),
+            tracking: None,
         }
     }
 
-    pub fn add_branch(&mut self) -> Result<()> {
+    // saves the selected branch and returns true, or returns false if
+    // it's a remote branch and creating a local branch for it has to
+    // be confirmed first. remote branches are saved by their local name.
+    pub fn add_branch(&mut self) -> Result<bool> {
         let new_branch = self.get_branch_name();
         if new_branch.is_empty() {
-            return Ok(());
+            return Ok(true);
         }
-        core::add_branch(self.project_path.as_str(), new_branch)?;
-        Ok(())
+        let new_branch = match self.git.remote_branch(new_branch.as_str())? {
+            Some(remote) if !self.git.local_branch_exists(remote.name.as_str())? => {
+                self.tracking = Some(remote);
+                return Ok(false);
+            }
+            Some(remote) => remote.name,
+            None => new_branch,
+        };
+        self.save_branch(new_branch)?;
+        Ok(true)
+    }
+
+    pub fn is_confirming(&self) -> bool {
+        self.tracking.is_some()
+    }
+
+    // creates the local branch tracking the remote one being added
+    // and saves it.
+    pub fn confirm_tracking(&mut self) -> Result<()> {
+        let Some(remote) = self.tracking.take() else {
+            return Ok(());
+        };
+        let branch = self.git.track_remote_branch(&remote)?;
+        self.save_branch(branch)
+    }
+
+    pub fn cancel_tracking(&mut self) {
+        self.tracking = None;
+    }
+
+    // tags and commits typed in the input are saved as such
+    fn save_branch(&mut self, branch: String) -> Result<()> {
+        let target = self.git.checkout_target(branch.as_str())?;
+        self.db
+            .borrow_mut()
+            .add_target(self.project_path.as_str(), target)
     }
 
     pub fn update_autocomplete(&mut self) {
         let items = self
             .all_branches
             .iter()
-            .filter(|b| b.contains(self.add_branch_input.as_str()))
+            .filter(|b| b.name.contains(self.add_branch_input.as_str()))
             .cloned()
-            .collect::<Vec<String>>();
+            .collect::<Vec<core::BranchRef>>();
         self.add_branch_autocomplete.set_items(items);
         self.add_branch_autocomplete.state.select(None)
     }
@@ -165,6 +183,10 @@ impl AddBranchWidget {
     }
 
     pub fn exit_context(&mut self) -> ExitContextResult {
+        if self.is_confirming() {
+            self.cancel_tracking();
+            return ExitContextResult::Continue;
+        }
         match self.add_branch_autocomplete.state.selected() {
             Some(_) => {
                 self.add_branch_autocomplete.state.select(None);
@@ -184,7 +206,7 @@ impl AddBranchWidget {
 
     pub fn get_branch_name(&self) -> String {
         match self.add_branch_autocomplete.state.selected() {
-            Some(i) => self.add_branch_autocomplete.items[i].clone(),
+            Some(i) => self.add_branch_autocomplete.items[i].name.clone(),
             None => self.add_branch_input.clone(),
         }
     }
@@ -203,16 +225,36 @@ impl AddBranchWidget {
             .constraints([Constraint::Length(3), Constraint::Min(3)].as_ref())
             .split(area);
 
+        let title = match &self.tracking {
+            Some(remote) => format!(
+                "Create branch {} tracking {}? (y/n)",
+                remote.name,
+                remote.upstream()
+            ),
+            None => "Add branch".to_string(),
+        };
         let input = Paragraph::new(self.add_branch_input.as_str())
-            .block(Block::default().title("Add branch").borders(Borders::ALL));
+            .block(Block::default().title(title).borders(Borders::ALL));
 
         f.render_widget(input, chunks[0]);
 
+        // local branches are listed first, labeled with their upstream
+        let label = Style::default().fg(Color::DarkGray);
         let items = self
             .add_branch_autocomplete
             .items
             .iter()
-            .map(|b| Text::raw(b.as_str()))
+            .map(|b| {
+                let kind = match b.kind {
+                    core::BranchKind::Local => "local  ",
+                    _ => "remote ",
+                };
+                let mut spans = vec![Span::styled(kind, label), Span::raw(b.name.as_str())];
+                if let Some(upstream) = &b.upstream {
+                    spans.push(Span::styled(format!(" -> {upstream}"), label));
+                }
+                Text::from(Line::from(spans))
+            })
             .collect::<Vec<Text>>();
 
         let list = List::new(items)
@@ -235,29 +277,148 @@ pub enum ChangeBranchesWidgetMode {
 
 pub struct ChangeBranchesWidget {
     pub mode: ChangeBranchesWidgetMode,
+    db: Rc<RefCell<core::DBHandle>>,
     project_path: String,
-    saved_branches: StatefulList<String>,
+    saved_branches: StatefulList<core::Branch>,
     input: String,
-    cur_branch: String,
+    // saved branches, tags or commits that are checked out
+    checked_out: Vec<String>,
+    // merge, rebase, etc. left in progress, checking out asks first
+    operation: Option<core::GitOperation>,
+    confirming_force: bool,
     git: core::Git,
+    checkout_options: core::CheckoutOptions,
+    // stash diffs by branch, loaded the first time a branch is selected
+    stash_previews: HashMap<String, Result<Option<String>, String>>,
+    // branch details, loaded the first time a branch is selected
+    branch_infos: HashMap<String, Result<core::BranchInfo, String>>,
+    preview_branch: Option<String>,
+    preview_scroll: u16,
 }
 
 impl ChangeBranchesWidget {
     pub fn new(
+        db: Rc<RefCell<core::DBHandle>>,
         project_path: String,
-        saved_branches: Vec<String>,
+        saved_branches: Vec<core::Branch>,
         git: core::Git,
+        checkout_options: core::CheckoutOptions,
     ) -> Result<ChangeBranchesWidget> {
         Ok(ChangeBranchesWidget {
             mode: ChangeBranchesWidgetMode::Normal,
+            db,
             project_path,
+            checked_out: git.checked_out(&saved_branches)?,
+            operation: git.operation_in_progress()?,
+            confirming_force: false,
             saved_branches: StatefulList::with_items(saved_branches),
             input: String::new(),
-            cur_branch: git.get_current_branch()?,
             git,
+            checkout_options,
+            stash_previews: HashMap::new(),
+            branch_infos: HashMap::new(),
+            preview_branch: None,
+            preview_scroll: 0,
         })
     }
 
+    fn selected_branch(&self) -> Option<String> {
+        self.saved_branches
+            .selected()
+            .and_then(|i| self.saved_branches.items().get(i))
+            .map(|b| b.name.clone())
+    }
+
+    pub fn scroll_preview_down(&mut self) {
+        self.preview_scroll = self.preview_scroll.saturating_add(10);
+    }
+
+    pub fn scroll_preview_up(&mut self) {
+        self.preview_scroll = self.preview_scroll.saturating_sub(10);
+    }
+
+    // forgets the loaded stash diffs, to be called when stashes
+    // may have changed.
+    pub fn clear_previews(&mut self) {
+        self.stash_previews.clear();
+    }
+
+    fn stash_preview(&mut self, branch: &str) -> Text<'static> {
+        let diff = self
+            .stash_previews
+            .entry(branch.to_string())
+            .or_insert_with(|| {
+                self.git
+                    .branch_stash_diff(branch)
+                    .map_err(|e| e.to_string())
+            });
+        match diff {
+            Ok(Some(diff)) => diff_text(diff.as_str()),
+            Ok(None) => Text::styled("no pending changes", Style::default().fg(Color::DarkGray)),
+            Err(err) => Text::raw(format!("could not load stash: {}", err.trim())),
+        }
+    }
+
+    fn branch_info(&mut self, branch: &str) -> Text<'static> {
+        let info = self
+            .branch_infos
+            .entry(branch.to_string())
+            .or_insert_with(|| self.git.branch_info(branch).map_err(|e| e.to_string()));
+        let info = match info {
+            Ok(info) => info,
+            Err(err) => return Text::raw(format!("could not load branch: {}", err.trim())),
+        };
+        let upstream = match &info.upstream {
+            Some(upstream) => format!("{upstream} (ahead {}, behind {})", info.ahead, info.behind),
+            None => "none".to_string(),
+        };
+        let label = Style::default().fg(Color::DarkGray);
+        Text::from(vec![
+            Line::from(info.subject.clone()),
+            Line::from(vec![
+                Span::styled("author:   ", label),
+                Span::raw(info.author.clone()),
+            ]),
+            Line::from(vec![
+                Span::styled("date:     ", label),
+                Span::raw(info.date.clone()),
+            ]),
+            Line::from(vec![Span::styled("upstream: ", label), Span::raw(upstream)]),
+        ])
+    }
+
+    fn draw_branch_info(&mut self, f: &mut Frame, area: Rect) {
+        let text = match self.selected_branch() {
+            Some(branch) => self.branch_info(branch.as_str()),
+            None => Text::raw(""),
+        };
+        let details =
+            Paragraph::new(text).block(Block::default().borders(Borders::ALL).title("Details"));
+
+        f.render_widget(details, area);
+    }
+
+    fn draw_stash_preview(&mut self, f: &mut Frame, area: Rect) {
+        let branch = self.selected_branch();
+        if branch != self.preview_branch {
+            self.preview_branch = branch.clone();
+            self.preview_scroll = 0;
+        }
+        let text = match branch {
+            Some(branch) => self.stash_preview(branch.as_str()),
+            None => Text::raw(""),
+        };
+        let preview = Paragraph::new(text)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Pending changes"),
+            )
+            .scroll((self.preview_scroll, 0));
+
+        f.render_widget(preview, area);
+    }
+
     pub fn next(&mut self) {
         self.saved_branches.next();
     }
@@ -267,27 +428,24 @@ impl ChangeBranchesWidget {
     }
 
     pub fn swap_down(&mut self) -> Result<()> {
-        self.saved_branches.swap_down();
-        core::set_branches(
-            self.project_path.as_str(),
-            self.saved_branches
-                .items()
-                .iter()
-                .map(|b| b.as_str())
-                .collect::<Vec<&str>>(),
-        )
+        self.move_selected(1)
     }
 
     pub fn swap_up(&mut self) -> Result<()> {
-        self.saved_branches.swap_up();
-        core::set_branches(
-            self.project_path.as_str(),
-            self.saved_branches
-                .items()
-                .iter()
-                .map(|b| b.as_str())
-                .collect::<Vec<&str>>(),
-        )
+        self.move_selected(-1)
+    }
+
+    fn move_selected(&mut self, delta: isize) -> Result<()> {
+        let Some(branch) = self.selected_branch() else {
+            return Ok(());
+        };
+        let moved =
+            self.db
+                .borrow_mut()
+                .move_branch(self.project_path.as_str(), branch.as_str(), delta)?;
+        self.reload_saved_branches()?;
+        self.saved_branches.select(Some(moved));
+        Ok(())
     }
 
     pub fn input_char(&mut self, c: char) {
@@ -298,7 +456,7 @@ impl ChangeBranchesWidget {
                 .items
                 .iter()
                 .enumerate()
-                .filter(|&(_, b)| b.starts_with(self.input.as_str()))
+                .filter(|&(_, b)| b.name.starts_with(self.input.as_str()))
                 .map(|(i, _)| i)
                 .next();
             self.saved_branches.select(found_ix)
@@ -315,16 +473,68 @@ impl ChangeBranchesWidget {
         self.input.clear();
     }
 
-    pub fn checkout_selected(&self) -> Result<()> {
+    // whether checking out has to be confirmed because of an
+    // operation in progress
+    pub fn needs_force(&self) -> bool {
+        self.operation.is_some() && !self.checkout_options.force
+    }
+
+    pub fn start_confirm_force(&mut self) {
+        self.confirming_force = true;
+    }
+
+    pub fn is_confirming(&self) -> bool {
+        self.confirming_force
+    }
+
+    pub fn confirm_force(&mut self) {
+        self.confirming_force = false;
+        self.checkout_options.force = true;
+    }
+
+    pub fn cancel_force(&mut self) {
+        self.confirming_force = false;
+    }
+
+    // returns the worktree to switch to for branches checked out in
+    // their own worktree, other branches are checked out in place.
+    pub fn checkout_selected(&self) -> Result<Option<PathBuf>> {
         let selected = self
             .saved_branches
             .selected()
             .ok_or(anyhow!("no branch selected"))?;
-        let branch = self.saved_branches.items()[selected].as_str();
-        if branch == self.cur_branch {
+        let branch = &self.saved_branches.items()[selected];
+        if branch.worktree {
+            return Ok(Some(self.git.branch_worktree(branch.name.as_str())?));
+        }
+        if self.checked_out.contains(&branch.name) {
+            return Ok(None);
+        }
+        self.git
+            .checkout(branch.name.as_str(), &self.checkout_options)?;
+        Ok(None)
+    }
+
+    // tags and commits can't be checked out in a worktree
+    pub fn toggle_worktree_selected(&mut self) -> Result<()> {
+        let Some(branch) = self
+            .saved_branches
+            .selected()
+            .map(|i| self.saved_branches.items()[i].clone())
+        else {
+            return Ok(());
+        };
+        if branch.kind != core::TargetKind::Branch {
             return Ok(());
         }
-        self.git.checkout(branch)?;
+        self.db.borrow_mut().set_worktree(
+            self.project_path.as_str(),
+            branch.name.as_str(),
+            !branch.worktree,
+        )?;
+        let selected = self.saved_branches.selected();
+        self.reload_saved_branches()?;
+        self.saved_branches.select(selected);
         Ok(())
     }
 
@@ -333,19 +543,23 @@ impl ChangeBranchesWidget {
             .saved_branches
             .selected()
             .ok_or(anyhow!("no branch selected"))?;
-        let branch = self.saved_branches.items()[selected].clone();
-        core::remove_branch(self.project_path.as_str(), branch)?;
+        let branch = self.saved_branches.items()[selected].name.clone();
+        self.db
+            .borrow_mut()
+            .remove_branch(self.project_path.as_str(), branch)?;
         self.reload_saved_branches()?;
         Ok(())
     }
 
     pub fn reload_saved_branches(&mut self) -> Result<()> {
-        self.saved_branches = StatefulList::with_items(
-            core::get_branches(self.project_path.as_str())?
-                .iter()
-                .map(|b| b.name.clone())
-                .collect::<Vec<String>>(),
-        );
+        let branches = self
+            .db
+            .borrow()
+            .project(self.project_path.as_str())?
+            .branches
+            .clone();
+        self.checked_out = self.git.checked_out(&branches)?;
+        self.saved_branches = StatefulList::with_items(branches);
         Ok(())
     }
 
@@ -355,12 +569,20 @@ impl ChangeBranchesWidget {
             .constraints([Constraint::Length(3), Constraint::Min(3)].as_ref())
             .split(area);
 
-        let input = match self.mode {
-            ChangeBranchesWidgetMode::Normal => Paragraph::new(self.project_path.as_str()).block(
-                Block::default()
-                    .title("Change branches")
-                    .borders(Borders::ALL),
+        let warning = Style::default().fg(Color::Red).add_modifier(Modifier::BOLD);
+        let title = match self.operation {
+            Some(op) if self.confirming_force => Span::styled(
+                format!("A {op} is in progress, check out anyway? (y/n)"),
+                warning,
             ),
+            Some(op) if self.needs_force() => {
+                Span::styled(format!("Change branches ({op} in progress)"), warning)
+            }
+            _ => Span::raw("Change branches"),
+        };
+        let input = match self.mode {
+            ChangeBranchesWidgetMode::Normal => Paragraph::new(self.project_path.as_str())
+                .block(Block::default().title(title).borders(Borders::ALL)),
             ChangeBranchesWidgetMode::Search => Paragraph::new(self.input.as_str())
                 .block(Block::default().title("searching").borders(Borders::ALL)),
         };
@@ -372,11 +594,27 @@ impl ChangeBranchesWidget {
             .items
             .iter()
             .map(|b| {
-                if *b == self.cur_branch {
-                    Text::styled(format!("{b} *"), Style::default().fg(Color::LightGreen))
-                } else {
-                    Text::raw(b)
+                // tags, commits and worktree branches are labeled,
+                // commits by their short sha
+                let (name, kind) = match b.kind {
+                    core::TargetKind::Branch if b.worktree => (b.name.as_str(), Some(" worktree")),
+                    core::TargetKind::Branch => (b.name.as_str(), None),
+                    core::TargetKind::Tag => (b.name.as_str(), Some(" tag")),
+                    core::TargetKind::Commit => (&b.name[..b.name.len().min(7)], Some(" commit")),
+                };
+                let mut spans = vec![Span::raw(name)];
+                if let Some(kind) = kind {
+                    spans.push(Span::styled(kind, Style::default().fg(Color::DarkGray)));
                 }
+                let checked_out = self.checked_out.contains(&b.name);
+                if checked_out {
+                    spans.push(Span::raw(" *"));
+                }
+                let mut text = Text::from(Line::from(spans));
+                if checked_out {
+                    text.patch_style(Style::default().fg(Color::LightGreen));
+                }
+                text
             })
             .collect::<Vec<Text>>();
 
@@ -389,6 +627,491 @@ impl ChangeBranchesWidget {
             )
             .highlight_symbol(">> ");
 
-        f.render_stateful_widget(list, chunks[1], &mut self.saved_branches.state);
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)].as_ref())
+            .split(chunks[1]);
+
+        f.render_stateful_widget(list, panes[0], &mut self.saved_branches.state);
+
+        let preview = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(6), Constraint::Min(3)].as_ref())
+            .split(panes[1]);
+
+        self.draw_branch_info(f, preview[0]);
+        self.draw_stash_preview(f, preview[1]);
+    }
+}
+
+fn diff_text(diff: &str) -> Text<'static> {
+    let lines = diff
+        .lines()
+        .map(|line| {
+            let style = if line.starts_with("+++") || line.starts_with("---") {
+                Style::default().add_modifier(Modifier::BOLD)
+            } else if line.starts_with('+') {
+                Style::default().fg(Color::Green)
+            } else if line.starts_with('-') {
+                Style::default().fg(Color::Red)
+            } else if line.starts_with("@@") {
+                Style::default().fg(Color::Cyan)
+            } else if line.starts_with("diff ") {
+                Style::default()
+                    .fg(Color::Yellow)
+                    .add_modifier(Modifier::BOLD)
+            } else {
+                Style::default()
+            };
+            Line::styled(line.to_string(), style)
+        })
+        .collect::<Vec<Line>>();
+    Text::from(lines)
+}
+
+pub struct ResolveConflictWidget {
+    conflict: core::StashConflict,
+    files: StatefulList<String>,
+    message: String,
+}
+
+impl ResolveConflictWidget {
+    pub fn new(conflict: core::StashConflict) -> ResolveConflictWidget {
+        let mut files = StatefulList::with_items(conflict.files.clone());
+        files.select(Some(0));
+        ResolveConflictWidget {
+            conflict,
+            files,
+            message: String::new(),
+        }
+    }
+
+    pub fn conflict(&self) -> &core::StashConflict {
+        &self.conflict
+    }
+
+    pub fn selected_file(&self) -> Option<&str> {
+        self.files
+            .selected()
+            .and_then(|i| self.files.items().get(i))
+            .map(|f| f.as_str())
+    }
+
+    pub fn set_message(&mut self, message: String) {
+        self.message = message;
+    }
+
+    pub fn next(&mut self) {
+        self.files.next();
+    }
+
+    pub fn previous(&mut self) {
+        self.files.previous();
+    }
+
+    pub fn draw(&mut self, f: &mut Frame, area: Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Min(3),
+                    Constraint::Length(3),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        let header = Paragraph::new(format!(
+            "Popping the stash for {} conflicted, the stash is kept until resolved",
+            self.conflict.branch
+        ))
+        .block(Block::default().title("Conflict").borders(Borders::ALL));
+
+        f.render_widget(header, chunks[0]);
+
+        let items = self
+            .files
+            .items
+            .iter()
+            .map(|file| Text::styled(file.as_str(), Style::default().fg(Color::LightRed)))
+            .collect::<Vec<Text>>();
+
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title("Conflicted files"),
+            )
+            .highlight_style(
+                Style::default()
+                    .bg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+
+        f.render_stateful_widget(list, chunks[1], &mut self.files.state);
+
+        let help = if self.message.is_empty() {
+            "e: edit file | r: mark resolved and drop stash | a: abort and keep stash | q: quit"
+        } else {
+            self.message.as_str()
+        };
+        let footer = Paragraph::new(help).block(Block::default().borders(Borders::ALL));
+
+        f.render_widget(footer, chunks[2]);
+    }
+}
+
+enum StashesWidgetInput {
+    None,
+    Rename,
+    Move,
+}
+
+pub struct StashesWidget {
+    db: Rc<RefCell<core::DBHandle>>,
+    project_path: String,
+    git: core::Git,
+    stashes: StatefulList<core::LgcStash>,
+    input_mode: StashesWidgetInput,
+    input: String,
+    message: String,
+}
+
+impl StashesWidget {
+    // stashes are loaded with `reload` when the widget is opened
+    pub fn new(
+        db: Rc<RefCell<core::DBHandle>>,
+        project_path: String,
+        git: core::Git,
+    ) -> StashesWidget {
+        StashesWidget {
+            db,
+            project_path,
+            git,
+            stashes: StatefulList::with_items(Vec::new()),
+            input_mode: StashesWidgetInput::None,
+            input: String::new(),
+            message: String::new(),
+        }
+    }
+
+    pub fn reload(&mut self) -> Result<()> {
+        let selected = self.stashes.selected().unwrap_or(0);
+        let stashes = self.git.lgc_stashes()?;
+        let len = stashes.len();
+        self.stashes.set_items(stashes);
+        self.stashes
+            .select(Some(selected.min(len.saturating_sub(1))).filter(|_| len > 0));
+        Ok(())
+    }
+
+    pub fn is_editing(&self) -> bool {
+        !matches!(self.input_mode, StashesWidgetInput::None)
+    }
+
+    pub fn next(&mut self) {
+        self.stashes.next();
+    }
+
+    pub fn previous(&mut self) {
+        self.stashes.previous();
+    }
+
+    fn selected_stash(&self) -> Option<core::LgcStash> {
+        self.stashes
+            .selected()
+            .and_then(|i| self.stashes.items().get(i))
+            .cloned()
+    }
+
+    // runs `f` on the selected stash and reports the result in the
+    // message line instead of failing, so a conflict doesn't close lgc.
+    fn run_on_selected(
+        &mut self,
+        done: &str,
+        f: impl FnOnce(&core::Git, &core::LgcStash) -> Result<()>,
+    ) -> Result<()> {
+        let Some(stash) = self.selected_stash() else {
+            return Ok(());
+        };
+        self.message = match f(&self.git, &stash) {
+            Ok(()) => format!("{done} stash for {}", stash.branch),
+            Err(err) => err
+                .to_string()
+                .split_whitespace()
+                .collect::<Vec<&str>>()
+                .join(" "),
+        };
+        self.reload()
+    }
+
+    pub fn apply_selected(&mut self) -> Result<()> {
+        self.run_on_selected("applied", |git, stash| git.apply_stash(stash))
+    }
+
+    pub fn pop_selected(&mut self) -> Result<()> {
+        self.run_on_selected("popped", |git, stash| git.pop_lgc_stash(stash))
+    }
+
+    pub fn drop_selected(&mut self) -> Result<()> {
+        self.run_on_selected("dropped", |git, stash| git.drop_stash(stash))
+    }
+
+    pub fn start_rename(&mut self) {
+        if let Some(stash) = self.selected_stash() {
+            self.input = stash.label;
+            self.input_mode = StashesWidgetInput::Rename;
+        }
+    }
+
+    pub fn start_move(&mut self) {
+        if self.selected_stash().is_some() {
+            self.input.clear();
+            self.input_mode = StashesWidgetInput::Move;
+        }
+    }
+
+    pub fn input_char(&mut self, c: char) {
+        self.input.push(c);
+    }
+
+    pub fn remove_char(&mut self) {
+        self.input.pop();
+    }
+
+    pub fn confirm_input(&mut self) -> Result<()> {
+        let input = std::mem::take(&mut self.input);
+        let mode = std::mem::replace(&mut self.input_mode, StashesWidgetInput::None);
+        match mode {
+            StashesWidgetInput::None => Ok(()),
+            StashesWidgetInput::Rename => self.run_on_selected("renamed", |git, stash| {
+                git.rename_stash(stash, input.as_str())
+            }),
+            StashesWidgetInput::Move => {
+                // stashes can also be moved to saved tags and commits
+                let targets = self
+                    .db
+                    .borrow()
+                    .project(self.project_path.as_str())?
+                    .branches
+                    .clone();
+                self.run_on_selected("moved", |git, stash| {
+                    git.move_stash(stash, input.as_str(), &targets)
+                })
+            }
+        }
+    }
+
+    pub fn exit_context(&mut self) -> ExitContextResult {
+        if self.is_editing() {
+            self.input_mode = StashesWidgetInput::None;
+            self.input.clear();
+            return ExitContextResult::Continue;
+        }
+        self.message.clear();
+        ExitContextResult::Exit
+    }
+
+    pub fn draw(&mut self, f: &mut Frame, area: Rect) {
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)].as_ref())
+            .split(area);
+
+        let items = self
+            .stashes
+            .items
+            .iter()
+            .map(|s| {
+                let text = format!(
+                    "{:<24} {:<16} {:>3} file(s) {}",
+                    s.branch, s.age, s.files, s.label
+                );
+                if s.tracked {
+                    Text::raw(text)
+                } else {
+                    Text::styled(text, Style::default().fg(Color::DarkGray))
+                }
+            })
+            .collect::<Vec<Text>>();
+
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL).title("Stashes"))
+            .highlight_style(
+                Style::default()
+                    .bg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+
+        f.render_stateful_widget(list, chunks[0], &mut self.stashes.state);
+
+        let footer = match self.input_mode {
+            StashesWidgetInput::None if self.message.is_empty() => Paragraph::new(
+                "a: apply | p: pop | d: drop | n: rename | m: move to branch | ESC: back",
+            )
+            .block(Block::default().borders(Borders::ALL)),
+            StashesWidgetInput::None => {
+                Paragraph::new(self.message.as_str()).block(Block::default().borders(Borders::ALL))
+            }
+            StashesWidgetInput::Rename => Paragraph::new(self.input.as_str())
+                .block(Block::default().title("Rename stash").borders(Borders::ALL)),
+            StashesWidgetInput::Move => Paragraph::new(self.input.as_str()).block(
+                Block::default()
+                    .title("Move stash to branch")
+                    .borders(Borders::ALL),
+            ),
+        };
+
+        f.render_widget(footer, chunks[1]);
+    }
+}
+
+// a project as listed in the project picker
+pub struct ProjectEntry {
+    pub project: core::Project,
+    // None when the repository can't be read
+    pub branch: Option<String>,
+    pub dirty: bool,
+}
+
+pub struct ProjectsWidget {
+    projects: Vec<ProjectEntry>,
+    input: String,
+    // indexes of the projects matching the input
+    matches: StatefulList<usize>,
+    // why the last picked project couldn't be opened
+    message: String,
+}
+
+impl ProjectsWidget {
+    pub fn new(projects: Vec<ProjectEntry>) -> ProjectsWidget {
+        let mut widget = ProjectsWidget {
+            projects,
+            input: String::new(),
+            matches: StatefulList::with_items(Vec::new()),
+            message: String::new(),
+        };
+        widget.update_matches();
+        widget
+    }
+
+    fn update_matches(&mut self) {
+        let matches = self
+            .projects
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| fuzzy_match(self.input.as_str(), p.project.path.as_str()))
+            .map(|(i, _)| i)
+            .collect::<Vec<usize>>();
+        self.matches.set_items(matches);
+    }
+
+    pub fn selected(&self) -> Option<&core::Project> {
+        let i = self.matches.selected()?;
+        let ix = self.matches.items().get(i)?;
+        Some(&self.projects[*ix].project)
+    }
+
+    pub fn set_message(&mut self, message: String) {
+        self.message = message.split_whitespace().collect::<Vec<&str>>().join(" ");
+    }
+
+    pub fn input_char(&mut self, c: char) {
+        self.input.push(c);
+        self.update_matches();
+    }
+
+    pub fn remove_char(&mut self) {
+        self.input.pop();
+        self.update_matches();
+    }
+
+    pub fn exit_context(&mut self) -> ExitContextResult {
+        if self.input.is_empty() {
+            return ExitContextResult::Exit;
+        }
+        self.input.clear();
+        self.update_matches();
+        ExitContextResult::Continue
+    }
+
+    pub fn next(&mut self) {
+        self.matches.next();
+    }
+
+    pub fn previous(&mut self) {
+        self.matches.previous();
+    }
+
+    pub fn draw(&mut self, f: &mut Frame, area: Rect) {
+        let footer = if self.message.is_empty() { 0 } else { 3 };
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(
+                [
+                    Constraint::Length(3),
+                    Constraint::Min(3),
+                    Constraint::Length(footer),
+                ]
+                .as_ref(),
+            )
+            .split(area);
+
+        let input = Paragraph::new(self.input.as_str())
+            .block(Block::default().title("Projects").borders(Borders::ALL));
+
+        f.render_widget(input, chunks[0]);
+
+        // the checked out branch follows the path, in yellow when
+        // there are changes
+        let label = Style::default().fg(Color::DarkGray);
+        let items = self
+            .matches
+            .items()
+            .iter()
+            .map(|ix| {
+                let entry = &self.projects[*ix];
+                let mut spans = vec![Span::raw(entry.project.path.as_str()), Span::raw(" ")];
+                match &entry.branch {
+                    Some(branch) if entry.dirty => spans.extend([
+                        Span::styled(branch.as_str(), Style::default().fg(Color::Yellow)),
+                        Span::styled(" modified", label),
+                    ]),
+                    Some(branch) => spans.push(Span::styled(branch.as_str(), label)),
+                    None => spans.push(Span::styled("not found", label)),
+                }
+                Text::from(Line::from(spans))
+            })
+            .collect::<Vec<Text>>();
+
+        let list = List::new(items)
+            .block(Block::default().borders(Borders::ALL))
+            .highlight_style(
+                Style::default()
+                    .bg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+            )
+            .highlight_symbol(">> ");
+
+        f.render_stateful_widget(list, chunks[1], &mut self.matches.state);
+
+        if !self.message.is_empty() {
+            let message =
+                Paragraph::new(self.message.as_str()).block(Block::default().borders(Borders::ALL));
+            f.render_widget(message, chunks[2]);
+        }
     }
 }
+
+// whether the characters of `input` appear in `text` in order,
+// ignoring case.
+fn fuzzy_match(input: &str, text: &str) -> bool {
+    let mut text = text.chars().flat_map(char::to_lowercase);
+    input
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|c| text.any(|t| t == c))
+}